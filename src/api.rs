@@ -1,67 +1,305 @@
-use crate::{types::*, store, rsm::RSM};
-use axum::extract::{Json, Path};
-use hyper::StatusCode;
+use crate::{types::*, compaction, store, store::ProposalOptions, watch, rsm, rsm::RSM};
+use crate::error::{Error, Result};
+use std::{collections::HashMap, env, time::Duration};
+use axum::{async_trait, body::Body, extract::{FromRequestParts, Json, Path, Query}, http::{request::Parts, Request}, middleware::Next};
+use axum::response::{IntoResponse, Response, sse::{Event, KeepAlive, Sse}};
+use hyper::{header, HeaderMap, StatusCode};
+use serde::Deserialize;
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
 
-/// Sequentially consistent read
-pub async fn handle_get(Path(key): Path<Key>) -> Json<GetResponse> {
-    let value = store::get(&key);
-    Json(GetResponse{key, value})
+lazy_static! {
+    static ref FORWARD_TO_LEADER: bool = if let Ok(var) = env::var("FORWARD_TO_LEADER") {
+        var.parse().expect("FORWARD_TO_LEADER must be true or false")
+    } else {
+        false
+    };
 }
 
-/// Delete key from store
-pub async fn handle_delete(Path(key): Path<Key>) -> (StatusCode, Json<PutResponse>) {
-    if let Ok(prev_kv) = store::delete(key).await {
-        return (StatusCode::OK, Json(PutResponse{ prev_kv }))
-    } else {
-        (StatusCode::INTERNAL_SERVER_ERROR, Json(PutResponse{ prev_kv: None }))
+/// Set on forwarded requests, so they are never forwarded twice
+const FORWARDED_BY: &str = "x-forwarded-by";
+
+/// Set on write responses to the global log index the write was applied at
+const LOG_INDEX: &str = "x-log-index";
+
+/// A write response together with its log index
+type Indexed<T> = ([(&'static str, String); 1], T);
+
+fn indexed<T>(resp: T, index: u64) -> Indexed<T> {
+    ([(LOG_INDEX, index.to_string())], resp)
+}
+
+#[derive(Deserialize)]
+pub struct StateParams {
+    config: u32,
+}
+
+#[derive(Deserialize)]
+struct TimeoutParams {
+    timeout: Option<u64>,
+}
+
+/// Reads how a write should be proposed from the request.
+/// The timeout is taken in millis from the `X-Request-Timeout` header or the `timeout` query parameter,
+/// without either the server default is used. `X-Session-Id` and `X-Session-Seq` make retries of the write safe.
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for store::ProposalOptions {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self> {
+        let timeout = if let Some(header) = parts.headers.get("x-request-timeout") {
+            let millis = header.to_str().ok().and_then(|h| h.parse().ok())
+                .ok_or(Error::InvalidRequest("timeout must be u64 in millis".to_owned()))?;
+            Some(millis)
+        } else {
+            Query::<TimeoutParams>::try_from_uri(&parts.uri)
+                .map_err(|_| Error::InvalidRequest("timeout must be u64 in millis".to_owned()))?
+                .timeout
+        };
+        let header = |name| parts.headers.get(name).map(|h| h.to_str().ok());
+        let session = match (header("x-session-id"), header("x-session-seq")) {
+            (None, None) => None,
+            (Some(Some(id)), Some(Some(seq))) if !id.is_empty() => {
+                let seq = seq.parse().map_err(|_| Error::InvalidRequest("session seq must be u64".to_owned()))?;
+                Some(Session{ id: id.to_owned(), seq })
+            },
+            _ => return Err(Error::InvalidRequest("sessions need both X-Session-Id and X-Session-Seq".to_owned())),
+        };
+        Ok(Self{ timeout: timeout.map(Duration::from_millis), session })
     }
 }
 
-/// Clear Store store
-pub async fn handle_clear() -> (StatusCode, Json<Option<()>>) {
-    //println!("Hello from handler");
-    if let Ok(_) = store::clear().await {
-        (StatusCode::OK, Json(None))
+/// With FORWARD_TO_LEADER set, followers pass writes and linearizable reads on to the leader and relay its response.
+/// Requests are handled locally if no other node is known to lead, or if they were already forwarded once.
+pub async fn forward_to_leader(req: Request<Body>, next: Next<Body>) -> Response {
+    if !*FORWARD_TO_LEADER || req.headers().contains_key(FORWARDED_BY) {
+        return next.run(req).await
+    }
+    let addr = match rsm::leader() {
+        Some((_, Some(addr))) => addr,
+        _ => return next.run(req).await,
+    };
+    match forward(addr, req).await {
+        Ok(resp) => resp,
+        Err(err) => err.into_response(),
+    }
+}
+
+/// Like `forward_to_leader`, for reads that only go to the leader if they ask for linearizable consistency
+pub async fn forward_linearizable_reads(req: Request<Body>, next: Next<Body>) -> Response {
+    let linearizable = Query::<ConsistencyParams>::try_from_uri(req.uri())
+        .is_ok_and(|Query(params)| params.consistency == Some(Consistency::Linearizable));
+    if linearizable {
+        forward_to_leader(req, next).await
     } else {
-        (StatusCode::INTERNAL_SERVER_ERROR, Json(None))
+        next.run(req).await
     }
 }
 
-/// Linearizable read
-pub async fn handle_linearizable_get(Path(key): Path<Key>) -> (StatusCode, Json<GetResponse>) {
-    if let Ok(value) = store::linearizable_get(&key).await {
-        (StatusCode::OK, Json(GetResponse{key, value}))
+/// Sends a request on to `addr` and returns its response
+async fn forward(addr: String, req: Request<Body>) -> Result<Response> {
+    let (parts, body) = req.into_parts();
+    let body = hyper::body::to_bytes(body).await.map_err(|err| Error::InvalidRequest(err.to_string()))?;
+    let path = parts.uri.path_and_query().map(|path| path.as_str()).unwrap_or("/");
+    let mut headers = parts.headers;
+    headers.remove(header::HOST);
+    headers.remove(header::TRANSFER_ENCODING);
+    headers.insert(FORWARDED_BY, rsm::pid().into());
+    let resp = reqwest::Client::new()
+        .request(parts.method, format!("http://{}{}", addr, path))
+        .headers(headers)
+        .body(body)
+        .send().await
+        .map_err(|err| Error::Forward(err.to_string()))?;
+    let status = resp.status();
+    let headers: Vec<_> = [header::CONTENT_TYPE.as_str(), LOG_INDEX].into_iter()
+        .filter_map(|name| resp.headers().get(name).map(|value| (name, value.clone())))
+        .collect();
+    let body = resp.bytes().await.map_err(|err| Error::Forward(err.to_string()))?;
+    let mut resp = (status, body).into_response();
+    for (name, value) in headers {
+        resp.headers_mut().insert(name, value);
+    }
+    Ok(resp)
+}
+
+/// The current leader as seen by this node
+pub async fn handle_leader() -> Json<LeaderResponse> {
+    let (leader, addr) = match rsm::leader() {
+        Some((leader, addr)) => (Some(leader), addr),
+        None => (None, None),
+    };
+    Json(LeaderResponse{ leader, addr, node: rsm::pid() })
+}
+
+/// Read with the requested consistency, optionally of a past revision
+pub async fn handle_get(Path(key): Path<Key>, Query(params): Query<GetParams>, Query(consistency): Query<ConsistencyParams>, opts: ProposalOptions) -> Result<Json<GetResponse>> {
+    store::catch_up(&consistency, opts.timeout).await?;
+    if let Some(revision) = params.revision {
+        Ok(Json(store::get_at(&key, revision)?))
     } else {
-        (StatusCode::INTERNAL_SERVER_ERROR, Json(GetResponse{key, value: None}))
+        Ok(Json(store::get(&key)?))
     }
 }
 
+/// Read of a key range or prefix with the requested consistency
+pub async fn handle_range(Query(req): Query<RangeRequest>, Query(consistency): Query<ConsistencyParams>, opts: ProposalOptions) -> Result<Json<RangeResponse>> {
+    store::catch_up(&consistency, opts.timeout).await?;
+    Ok(Json(store::range(&req)?))
+}
+
+/// Streams changes of a key or prefix as Server-Sent Events.
+/// Every event carries its revision as id, so clients can resume via `Last-Event-ID` or `start_revision`.
+pub async fn handle_watch(Query(req): Query<WatchRequest>, headers: HeaderMap) -> Result<Sse<impl Stream<Item = std::result::Result<Event, serde_json::Error>>>> {
+    let last_event_id = headers.get("last-event-id")
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| id.parse::<Revision>().ok());
+    let start_revision = last_event_id.map(|id| id + 1).or(req.start_revision);
+    let (backlog, rx) = watch::subscribe(req.key, req.prefix, start_revision)?;
+    let events = tokio_stream::iter(backlog)
+        .chain(ReceiverStream::new(rx))
+        .map(|event| Event::default().id(event.revision.to_string()).json_data(event));
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// Delete key from store
+pub async fn handle_delete(Path(key): Path<Key>, opts: ProposalOptions) -> Result<Indexed<Json<PutResponse>>> {
+    let (resp, index) = store::delete(key, &opts).await?;
+    Ok(indexed(Json(resp), index))
+}
+
+/// Clear Store store
+pub async fn handle_clear(opts: ProposalOptions) -> Result<Indexed<Json<Option<()>>>> {
+    let index = store::clear(&opts).await?;
+    Ok(indexed(Json(None), index))
+}
+
+/// Linearizable read
+pub async fn handle_linearizable_get(Path(key): Path<Key>, Query(params): Query<ConsistencyParams>, opts: ProposalOptions) -> Result<Json<GetResponse>> {
+    let params = ConsistencyParams{ consistency: Some(Consistency::Linearizable), ..params };
+    store::catch_up(&params, opts.timeout).await?;
+    Ok(Json(store::get(&key)?))
+}
+
 /// Write and return previous value
-pub async fn handle_put(Json(req): Json<PutRequest>) -> (StatusCode, Json<PutResponse>) {
+pub async fn handle_put(opts: ProposalOptions, Json(req): Json<PutRequest>) -> Result<Indexed<Json<PutResponse>>> {
     let kv = KeyValue{key: req.key.clone(), value: req.value};
-    if let Ok(prev_kv) = store::put(kv).await {
-        (StatusCode::OK, Json(PutResponse{ prev_kv }))
-    } else {
-        (StatusCode::INTERNAL_SERVER_ERROR, Json(PutResponse{ prev_kv: None }))
-    }
+    let (resp, index) = store::put(kv, req.lease, &opts).await?;
+    Ok(indexed(Json(resp), index))
 }
 
 /// Linearizable Compare and Swap
-pub async fn handle_cas(Json(req): Json<CASRequest>) -> (StatusCode, Json<PutResponse>) {
-    if let Ok(prev_kv) = store::cas(req.key, req.new_value, req.expected_value).await {
-        (StatusCode::OK, Json(PutResponse{ prev_kv }))
-    } else {
-        (StatusCode::INTERNAL_SERVER_ERROR, Json(PutResponse{ prev_kv: None }))
+/// Reports whether the swap happened, a failed CAS is not an error
+pub async fn handle_cas(opts: ProposalOptions, Json(req): Json<CASRequest>) -> Result<Indexed<Json<CASResponse>>> {
+    let condition = req.condition()
+        .ok_or(Error::InvalidRequest("a CAS needs exactly one of expected_value, expected_version, expected_mod_revision or create_only".to_owned()))?;
+    let (resp, index) = store::cas(req.key, req.new_value, condition, &opts).await?;
+    Ok(indexed(Json(resp), index))
+}
+
+/// Atomic multi-key transaction with compares and success/failure branches
+pub async fn handle_txn(opts: ProposalOptions, Json(req): Json<TxnRequest>) -> Result<Indexed<Json<TxnResponse>>> {
+    let (resp, index) = store::txn(req, &opts).await?;
+    Ok(indexed(Json(resp), index))
+}
+
+/// Grant a new lease
+pub async fn handle_lease_grant(opts: ProposalOptions, Json(req): Json<LeaseGrantRequest>) -> Result<Indexed<Json<LeaseResponse>>> {
+    let (resp, index) = store::lease_grant(req.ttl, &opts).await?;
+    Ok(indexed(Json(resp), index))
+}
+
+/// Refresh the TTL of a lease
+pub async fn handle_lease_keep_alive(Path(id): Path<LeaseId>, opts: ProposalOptions) -> Result<Indexed<Json<LeaseResponse>>> {
+    let (resp, index) = store::lease_keep_alive(id, &opts).await?;
+    Ok(indexed(Json(resp), index))
+}
+
+/// Revoke a lease and delete its keys
+pub async fn handle_lease_revoke(Path(id): Path<LeaseId>, opts: ProposalOptions) -> Result<Indexed<StatusCode>> {
+    let index = store::lease_revoke(id, &opts).await?;
+    Ok(indexed(StatusCode::OK, index))
+}
+
+/// Remaining time to live of a lease
+pub async fn handle_lease_ttl(Path(id): Path<LeaseId>) -> Result<Json<LeaseResponse>> {
+    Ok(Json(store::get_lease(id)?))
+}
+
+/// Current cluster configuration
+pub async fn handle_get_config() -> Json<ConfigResponse> {
+    let (config_id, nodes) = rsm::config();
+    Json(ConfigResponse{ config_id, nodes })
+}
+
+/// Proposes a new configuration and responds once this node has left the old one
+async fn reconfigure(mut nodes: Vec<u64>, addrs: HashMap<u64, String>) -> Result<Json<ConfigResponse>> {
+    nodes.sort();
+    nodes.dedup();
+    if nodes.is_empty() {
+        return Err(Error::InvalidRequest("a configuration needs at least one node".to_owned()))
     }
+    let (config_id, nodes) = rsm::reconfigure(nodes, addrs).await?;
+    Ok(Json(ConfigResponse{ config_id, nodes }))
 }
 
-/// Linearizable Compare and Swap
-pub async fn handle_snapshot() -> StatusCode {
-    if let Ok(_) = store::snapshot().await {
-        StatusCode::OK
-    } else {
-        StatusCode::INTERNAL_SERVER_ERROR
+/// Replace the whole cluster membership
+pub async fn handle_reconfigure(Json(req): Json<ReconfigureRequest>) -> Result<Json<ConfigResponse>> {
+    let nodes = req.nodes.keys().cloned().collect();
+    reconfigure(nodes, req.nodes).await
+}
+
+/// Add a node to the cluster
+pub async fn handle_add_node(Json(req): Json<AddNodeRequest>) -> Result<Json<ConfigResponse>> {
+    let (_, mut nodes) = rsm::config();
+    nodes.push(req.id);
+    reconfigure(nodes, HashMap::from([(req.id, req.addr)])).await
+}
+
+/// Remove a node from the cluster
+pub async fn handle_remove_node(Path(id): Path<u64>) -> Result<Json<ConfigResponse>> {
+    let (_, mut nodes) = rsm::config();
+    if !nodes.contains(&id) {
+        return Err(Error::InvalidRequest(format!("node {} is not part of the cluster", id)))
     }
+    nodes.retain(|node| *node != id);
+    reconfigure(nodes, HashMap::new()).await
+}
+
+/// Replace one node of the cluster with a new one
+pub async fn handle_replace_node(Json(req): Json<ReplaceNodeRequest>) -> Result<Json<ConfigResponse>> {
+    let (_, mut nodes) = rsm::config();
+    if !nodes.contains(&req.old) {
+        return Err(Error::InvalidRequest(format!("node {} is not part of the cluster", req.old)))
+    }
+    nodes.retain(|node| *node != req.old);
+    nodes.push(req.new);
+    reconfigure(nodes, HashMap::from([(req.new, req.addr)])).await
+}
+
+/// Compact the decided log into a snapshot
+pub async fn handle_snapshot() -> Result<StatusCode> {
+    compaction::manual_snapshot().await?;
+    Ok(StatusCode::OK)
+}
+
+/// Trim the log of the whole cluster, once every replica has applied the trimmed entries
+pub async fn handle_trim(Json(req): Json<TrimRequest>) -> Result<Json<TrimResponse>> {
+    Ok(Json(TrimResponse{ index: store::trim(req.index).await? }))
+}
+
+/// The global log index this node has applied up to, so the leader knows what can be trimmed
+pub async fn handle_applied_index() -> Json<u64> {
+    Json(store::applied_index())
+}
+
+/// Serves this node's store to a peer that fell behind a trim of the log
+pub async fn handle_state(Query(params): Query<StateParams>) -> Json<Option<store::StoreState>> {
+    Json(store::state(params.config))
+}
+
+/// The state of the snapshot policy and its last run
+pub async fn handle_snapshot_status() -> Json<SnapshotStatus> {
+    Json(compaction::status())
 }
 
 /// Linearizable Compare and Swap
@@ -0,0 +1,148 @@
+use crate::rsm::RSMCommand;
+use crate::types::*;
+use crate::error::{Error, Result};
+use std::cmp::Ordering;
+
+/// An existing key, as far as commands on it depend on its state. The store and snapshots
+/// both apply commands through the functions below, so the commands have the same effect in both.
+pub trait Versioned {
+    fn value(&self) -> &Value;
+    fn version(&self) -> u64;
+    /// where the key was created, a revision in the store and an offset in snapshots
+    fn created(&self) -> u64;
+    /// the revision of the last change, `None` while it is not known, like in snapshots
+    fn mod_revision(&self) -> Option<Revision>;
+}
+
+/// Checks a compare against the current state of its key,
+/// `None` if that depends on a mod revision that is not known
+pub fn compare<K: Versioned>(current: Option<&K>, op: &CompareOp, target: &CompareTarget) -> Option<bool> {
+    let ordering = match target {
+        CompareTarget::Value(value) => match current {
+            Some(current) => current.value().cmp(value),
+            // missing keys only compare not equal to any value
+            None => return Some(*op == CompareOp::NotEqual),
+        },
+        CompareTarget::Version(version) => current.map_or(0, |current| current.version()).cmp(version),
+        CompareTarget::ModRevision(mod_revision) => match current {
+            Some(current) => current.mod_revision()?.cmp(mod_revision),
+            None => 0.cmp(mod_revision),
+        },
+        CompareTarget::Exists(exists) => current.is_some().cmp(exists),
+    };
+    Some(match op {
+        CompareOp::Equal => ordering == Ordering::Equal,
+        CompareOp::NotEqual => ordering != Ordering::Equal,
+        CompareOp::Greater => ordering == Ordering::Greater,
+        CompareOp::Less => ordering == Ordering::Less,
+    })
+}
+
+/// Checks the condition of a CAS against the current state of its key,
+/// `None` if that depends on a mod revision that is not known
+pub fn cas_holds<K: Versioned>(current: Option<&K>, condition: &CASCondition) -> Option<bool> {
+    let target = match condition {
+        CASCondition::Value(value) => CompareTarget::Value(value.clone()),
+        CASCondition::Absent => CompareTarget::Exists(false),
+        CASCondition::Version(version) => CompareTarget::Version(*version),
+        CASCondition::ModRevision(mod_revision) => CompareTarget::ModRevision(*mod_revision),
+    };
+    compare(current, &CompareOp::Equal, &target)
+}
+
+/// Where a key is created and its version, after a value is written onto it at `at`
+pub fn written<K: Versioned>(prev: Option<&K>, at: u64) -> (u64, u64) {
+    match prev {
+        Some(prev) => (prev.created(), prev.version() + 1),
+        None => (at, 1),
+    }
+}
+
+/// Keys that commands are applied to, by the store or a snapshot. A snapshot does not know the state of
+/// every key or lease, then the commands that depend on it are kept instead of applied.
+pub trait Keyspace {
+    type State: Versioned;
+
+    /// The current state of a key, `None` if it is not known
+    fn state(&self, key: &Key) -> Option<Option<&Self::State>>;
+
+    /// Whether a lease exists, `None` if it is not known
+    fn lease_exists(&self, id: LeaseId) -> Option<bool>;
+
+    /// Writes a value onto a key, attached to a lease, or deletes the key with `None`
+    fn write(&mut self, key: &Key, value: Option<(&Value, Option<LeaseId>)>, at: u64);
+
+    /// Reads a range in a transaction, only the store answers it
+    fn range(&mut self, _req: &RangeRequest, _at: u64) {}
+}
+
+/// Whether a value can be put onto a key, `None` if that depends on a state or lease that is not known
+fn check_put<K: Keyspace>(keys: &K, key: &Key, lease: Option<LeaseId>) -> Option<Result<()>> {
+    if let Some(id) = lease {
+        if !keys.lease_exists(id)? {
+            return Some(Err(Error::LeaseNotFound(id)))
+        }
+    }
+    keys.state(key)?;
+    Some(Ok(()))
+}
+
+/// Applies a put, delete, CAS or transaction at `at`. Returns whether a CAS or the compares of a transaction
+/// held, or an error if the command failed without any effect. Without applying anything, returns `None`
+/// if the outcome depends on a state or lease that is not known.
+pub fn apply<K: Keyspace>(keys: &mut K, cmd: &RSMCommand, at: u64) -> Option<Result<bool>> {
+    match cmd {
+        RSMCommand::Put((_, kv, lease)) => {
+            if let Err(err) = check_put(keys, &kv.key, *lease)? {
+                return Some(Err(err))
+            }
+            keys.write(&kv.key, Some((&kv.value, *lease)), at);
+            Some(Ok(true))
+        },
+        RSMCommand::Delete((_, key)) => {
+            keys.write(key, None, at);
+            Some(Ok(true))
+        },
+        RSMCommand::CAS((_, kv, condition)) => {
+            let holds = cas_holds(keys.state(&kv.key)?, condition)?;
+            if holds {
+                keys.write(&kv.key, Some((&kv.value, None)), at);
+            }
+            Some(Ok(holds))
+        },
+        RSMCommand::Txn((_, txn)) => apply_txn(keys, txn, at),
+        cmd => unreachable!("not a key command: {:?}", cmd),
+    }
+}
+
+/// Runs one branch of a transaction, depending on its compares. It is only applied once the state
+/// of every key it touches is known, and fails without changing anything if the branch puts a key onto
+/// a lease that does not exist.
+fn apply_txn<K: Keyspace>(keys: &mut K, txn: &TxnRequest, at: u64) -> Option<Result<bool>> {
+    for key in txn.keys() {
+        keys.state(&key)?;
+    }
+    let mut succeeded = true;
+    for cmp in txn.compare.iter() {
+        if !compare(keys.state(&cmp.key)?, &cmp.op, &cmp.target)? {
+            succeeded = false;
+            break
+        }
+    }
+    let ops = if succeeded { &txn.success } else { &txn.failure };
+    for op in ops {
+        if let TxnOp::Put(req) = op {
+            if let Err(err) = check_put(keys, &req.key, req.lease)? {
+                return Some(Err(err))
+            }
+        }
+    }
+    for op in ops {
+        match op {
+            TxnOp::Put(req) => keys.write(&req.key, Some((&req.value, req.lease)), at),
+            TxnOp::Delete { key } => keys.write(key, None, at),
+            TxnOp::Range(req) => keys.range(req, at),
+        }
+    }
+    Some(Ok(succeeded))
+}
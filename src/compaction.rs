@@ -0,0 +1,195 @@
+use crate::types::*;
+use crate::{rsm, rsm::RSM, store};
+use crate::error::Result;
+use omnipaxos_core::util::LogEntry;
+use std::{env, sync::{Arc, Mutex}, time::{SystemTime, UNIX_EPOCH}};
+use tokio::time::{self, Duration, Instant};
+
+lazy_static! {
+    /// how often the snapshot policy checks the log, in millis
+    static ref SNAPSHOT_INTERVAL: u64 = if let Ok(var) = env::var("SNAPSHOT_INTERVAL") {
+        var.parse().expect("SNAPSHOT_INTERVAL must be u64 in millis")
+    } else {
+        1000
+    };
+
+    /// snapshot once this many decided entries are not compacted yet, 0 disables the limit
+    static ref SNAPSHOT_ENTRIES: u64 = if let Ok(var) = env::var("SNAPSHOT_ENTRIES") {
+        var.parse().expect("SNAPSHOT_ENTRIES must be u64")
+    } else {
+        10000
+    };
+
+    /// snapshot once the decided entries that are not compacted yet take this many bytes as json, 0 disables the limit
+    static ref SNAPSHOT_BYTES: u64 = if let Ok(var) = env::var("SNAPSHOT_BYTES") {
+        var.parse().expect("SNAPSHOT_BYTES must be u64")
+    } else {
+        0
+    };
+
+    /// snapshot once the oldest entry that is not compacted yet is this old, in seconds, 0 disables the limit
+    static ref SNAPSHOT_AGE: u64 = if let Ok(var) = env::var("SNAPSHOT_AGE") {
+        var.parse().expect("SNAPSHOT_AGE must be u64 in seconds")
+    } else {
+        0
+    };
+
+    /// only compact this node's log, instead of the log of the whole cluster
+    static ref SNAPSHOT_LOCAL_ONLY: bool = if let Ok(var) = env::var("SNAPSHOT_LOCAL_ONLY") {
+        var.parse().expect("SNAPSHOT_LOCAL_ONLY must be true or false")
+    } else {
+        false
+    };
+
+    /// snapshots are skipped while a peer is more entries than this behind, or cannot be reached
+    static ref SNAPSHOT_MAX_LAG: u64 = if let Ok(var) = env::var("SNAPSHOT_MAX_LAG") {
+        var.parse().expect("SNAPSHOT_MAX_LAG must be u64")
+    } else {
+        1000
+    };
+}
+
+static mut INSTANCE: Option<Arc<Mutex<Compaction>>> = None;
+
+/// State of the snapshot policy
+#[derive(Debug)]
+struct Compaction {
+    config_id: u32,
+    compacted_idx: u64,
+    /// the log index up to which `bytes` has been counted
+    counted_idx: u64,
+    bytes: u64,
+    /// when the first entry after the last compaction was seen
+    oldest: Option<Instant>,
+    last_run: Option<SnapshotRun>,
+}
+
+impl Compaction {
+    fn instance() -> Arc<Mutex<Self>> {
+        unsafe {
+            if let Some(ref compaction) = INSTANCE {
+                compaction.clone()
+            } else {
+                let compaction = Arc::new(Mutex::new(Compaction{
+                    config_id: 0,
+                    compacted_idx: 0,
+                    counted_idx: 0,
+                    bytes: 0,
+                    oldest: None,
+                    last_run: None,
+                }));
+                INSTANCE = Some(compaction.clone());
+                compaction
+            }
+        }
+    }
+
+    /// Catches up with the log, and tells why a snapshot is due, if it is
+    fn due(&mut self) -> Option<String> {
+        let unlocked = RSM::instance();
+        let rsm = unlocked.lock().unwrap();
+        let compacted_idx = rsm.omnipaxos.get_compacted_idx();
+        let decided_idx = rsm.omnipaxos.get_decided_idx();
+        // a new configuration or a snapshot starts a new log to measure
+        if rsm.config_id != self.config_id || compacted_idx != self.compacted_idx {
+            self.config_id = rsm.config_id;
+            self.compacted_idx = compacted_idx;
+            self.counted_idx = compacted_idx;
+            self.bytes = 0;
+            self.oldest = None;
+        }
+        if decided_idx > self.counted_idx {
+            self.oldest.get_or_insert(Instant::now());
+            if *SNAPSHOT_BYTES > 0 {
+                for entry in rsm.omnipaxos.read_decided_suffix(self.counted_idx).unwrap_or_default() {
+                    if let LogEntry::Decided(cmd) = entry {
+                        self.bytes += serde_json::to_vec(&cmd).map_or(0, |bytes| bytes.len() as u64);
+                    }
+                }
+            }
+            self.counted_idx = decided_idx;
+        }
+        let entries = decided_idx - compacted_idx;
+        if *SNAPSHOT_ENTRIES > 0 && entries >= *SNAPSHOT_ENTRIES {
+            Some(format!("{} entries", entries))
+        } else if *SNAPSHOT_BYTES > 0 && self.bytes >= *SNAPSHOT_BYTES {
+            Some(format!("{} bytes", self.bytes))
+        } else if *SNAPSHOT_AGE > 0 && self.oldest.is_some_and(|oldest| oldest.elapsed() >= Duration::from_secs(*SNAPSHOT_AGE)) {
+            Some(format!("older than {}s", *SNAPSHOT_AGE))
+        } else {
+            None
+        }
+    }
+}
+
+/// Milliseconds since the unix epoch
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64)
+}
+
+/// Takes a snapshot and records it as the last run
+async fn snapshot(reason: String, local_only: bool) -> Result<()> {
+    let decided_index = rsm::decided_index();
+    let result = store::snapshot(local_only).await;
+    let outcome = match result {
+        Ok(()) => SnapshotOutcome::Compacted,
+        Err(ref err) => SnapshotOutcome::Failed(err.to_string()),
+    };
+    let run = SnapshotRun{ at: now_millis(), reason, decided_index, local_only, outcome };
+    Compaction::instance().lock().unwrap().last_run = Some(run);
+    result
+}
+
+/// Snapshot requested by an operator
+pub async fn manual_snapshot() -> Result<()> {
+    snapshot("manual".to_owned(), false).await
+}
+
+/// The snapshot policy. Every node may compact its own log, but only the leader compacts the log of the whole cluster.
+pub async fn run() {
+    let mut interval = time::interval(Duration::from_millis(*SNAPSHOT_INTERVAL));
+    loop {
+        interval.tick().await;
+        if !*SNAPSHOT_LOCAL_ONLY && !rsm::is_leader() {
+            continue
+        }
+        let reason = match Compaction::instance().lock().unwrap().due() {
+            Some(reason) => reason,
+            None => continue,
+        };
+        // lagging followers would need the whole snapshot instead of the entries they are missing,
+        // and a follower that cannot be reached may lag arbitrarily far behind
+        let decided_index = rsm::decided_index();
+        let indexes: Option<Vec<u64>> = rsm::query_peers::<u64>("/decided_index").await.into_iter().collect();
+        let lag = indexes.map(|indexes| indexes.into_iter()
+            .map(|index| decided_index.saturating_sub(index))
+            .max()
+            .unwrap_or(0));
+        let skipped = match lag {
+            None => Some("a follower could not be reached".to_owned()),
+            Some(lag) if lag > *SNAPSHOT_MAX_LAG => Some(format!("a follower is {} entries behind", lag)),
+            Some(_) => None,
+        };
+        if let Some(skipped) = skipped {
+            let outcome = SnapshotOutcome::Skipped(skipped);
+            let run = SnapshotRun{ at: now_millis(), reason, decided_index, local_only: *SNAPSHOT_LOCAL_ONLY, outcome };
+            Compaction::instance().lock().unwrap().last_run = Some(run);
+            continue
+        }
+        let _ = snapshot(reason, *SNAPSHOT_LOCAL_ONLY).await;
+    }
+}
+
+/// What the snapshot policy currently sees, and what it did last
+pub fn status() -> SnapshotStatus {
+    let unlocked = Compaction::instance();
+    let mut compaction = unlocked.lock().unwrap();
+    compaction.due();
+    let unlocked = RSM::instance();
+    let rsm = unlocked.lock().unwrap();
+    SnapshotStatus{
+        entries: rsm.omnipaxos.get_decided_idx() - rsm.omnipaxos.get_compacted_idx(),
+        bytes: compaction.bytes,
+        last_run: compaction.last_run.clone(),
+    }
+}
@@ -0,0 +1,104 @@
+use crate::types::{Revision, LeaseId};
+use axum::{extract::Json, response::{IntoResponse, Response}};
+use hyper::StatusCode;
+use serde::{Serialize, Deserialize};
+use std::fmt;
+
+/// Everything that can go wrong while serving a request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Error {
+    /// there is currently no leader that could take the proposal, retry later
+    NoLeader,
+    /// the proposal was not decided, e.g. because of a leader or configuration change, retry later
+    ProposalDropped,
+    /// the operation did not finish in time, it may still take effect
+    Timeout,
+    /// the requested revision has been compacted, the oldest readable revision is given
+    Compacted(Revision),
+    /// the log could not be compacted
+    Compaction(String),
+    LeaseNotFound(LeaseId),
+    /// a session command was retried, but the result of its first attempt is no longer known
+    Duplicate,
+    /// the replica lags further behind the leader than a bounded staleness read allows
+    Stale(String),
+    /// the request could not be forwarded to the leader, it may still take effect
+    Forward(String),
+    Storage(String),
+    InvalidRequest(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::NoLeader => StatusCode::SERVICE_UNAVAILABLE,
+            Self::ProposalDropped => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            Self::Compacted(_) => StatusCode::GONE,
+            Self::Compaction(_) => StatusCode::CONFLICT,
+            Self::LeaseNotFound(_) => StatusCode::NOT_FOUND,
+            Self::Duplicate => StatusCode::CONFLICT,
+            Self::Stale(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Forward(_) => StatusCode::BAD_GATEWAY,
+            Self::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    /// Stable name of the error, for clients to match on
+    fn code(&self) -> &'static str {
+        match self {
+            Self::NoLeader => "no_leader",
+            Self::ProposalDropped => "proposal_dropped",
+            Self::Timeout => "timeout",
+            Self::Compacted(_) => "compacted",
+            Self::Compaction(_) => "compaction",
+            Self::LeaseNotFound(_) => "lease_not_found",
+            Self::Duplicate => "duplicate",
+            Self::Stale(_) => "stale",
+            Self::Forward(_) => "forward",
+            Self::Storage(_) => "storage",
+            Self::InvalidRequest(_) => "invalid_request",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NoLeader => write!(f, "no leader, retry later"),
+            Self::ProposalDropped => write!(f, "proposal was dropped, retry later"),
+            Self::Timeout => write!(f, "timed out, the operation may still take effect"),
+            Self::Compacted(revision) => write!(f, "revision has been compacted, oldest available revision is {}", revision),
+            Self::Compaction(reason) => write!(f, "could not compact the log: {}", reason),
+            Self::LeaseNotFound(id) => write!(f, "lease {} not found", id),
+            Self::Duplicate => write!(f, "already applied, the result is no longer available"),
+            Self::Stale(reason) => write!(f, "replica is too stale, retry or read elsewhere: {}", reason),
+            Self::Forward(reason) => write!(f, "could not forward to the leader, the operation may still take effect: {}", reason),
+            Self::Storage(reason) => write!(f, "storage failure: {}", reason),
+            Self::InvalidRequest(reason) => write!(f, "invalid request: {}", reason),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Self::Storage(err.to_string())
+    }
+}
+
+/// JSON body of all error responses
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub error: String,
+    pub message: String,
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let body = ErrorResponse{ error: self.code().to_owned(), message: self.to_string() };
+        (self.status(), Json(body)).into_response()
+    }
+}
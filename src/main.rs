@@ -1,14 +1,22 @@
+// CAS and RSM keep their established names, EventKind::CAS is part of the JSON of watch events
+#![allow(clippy::upper_case_acronyms)]
+
 use crate::api::*;
-use axum::{routing::{get, post, put, delete}, Router};
+use axum::{middleware, routing::{get, post, put, delete}, Router};
 use hyper::StatusCode;
 use tokio::time::sleep;
 use std::{env, net::{SocketAddr, IpAddr, Ipv4Addr}, process::exit, time::Duration};
 
 mod types;
+mod error;
 mod api;
+mod apply;
+mod compaction;
 mod rsm;
 mod snapshot;
+mod storage;
 mod store;
+mod watch;
 
 #[macro_use]
 extern crate lazy_static;
@@ -31,23 +39,59 @@ pub async fn handle_crash() -> StatusCode {
 
 #[tokio::main]
 async fn main() {
-    let router = Router::new()
-        .route("/omnipaxos", post(rsm::handle_msg_http))
-        .route("/crash", post(handle_crash))
-        .route("/print_log", get(handle_print_log))
+    // writes and linearizable reads, which followers may forward to the leader
+    let leader_routes = Router::new()
         .route("/put", put(handle_put))
         .route("/cas", post(handle_cas))
-        .route("/get/:key", get(handle_get))
+        .route("/txn", post(handle_txn))
         .route("/delete/:key", delete(handle_delete))
         .route("/linearizable/get/:key", get(handle_linearizable_get))
+        .route("/lease/grant", post(handle_lease_grant))
+        .route("/lease/keepalive/:id", post(handle_lease_keep_alive))
+        .route("/lease/revoke/:id", post(handle_lease_revoke))
+        .route("/clear", post(handle_clear))
+        .route_layer(middleware::from_fn(forward_to_leader));
+
+    // reads that followers forward to the leader if they are linearizable
+    let read_routes = Router::new()
+        .route("/get/:key", get(handle_get))
+        .route("/range", get(handle_range))
+        .route_layer(middleware::from_fn(forward_linearizable_reads));
+
+    let router = Router::new()
+        .route("/omnipaxos", post(rsm::handle_msg_http))
+        .route("/heartbeat", get(rsm::handle_heartbeat))
+        .route("/read_index", get(rsm::handle_read_index))
+        .route("/decided_index", get(rsm::handle_decided_index))
+        .route("/applied_index", get(handle_applied_index))
+        .route("/state", get(handle_state))
+        .route("/crash", post(handle_crash))
+        .route("/print_log", get(handle_print_log))
+        .route("/leader", get(handle_leader))
+        .route("/watch", get(handle_watch))
+        .route("/lease/:id", get(handle_lease_ttl))
         .route("/snapshot", post(handle_snapshot))
-        .route("/clear", post(handle_clear));
+        .route("/snapshot/status", get(handle_snapshot_status))
+        .route("/admin/config", get(handle_get_config))
+        .route("/admin/reconfigure", post(handle_reconfigure))
+        .route("/admin/add_node", post(handle_add_node))
+        .route("/admin/remove_node/:id", post(handle_remove_node))
+        .route("/admin/replace_node", post(handle_replace_node))
+        .route("/admin/trim", post(handle_trim))
+        .merge(leader_routes)
+        .merge(read_routes);
 
     // rsm::RSM::instance();
 
     // start event loop
     tokio::spawn(rsm::run());
 
+    // keep the store up to date for watchers
+    tokio::spawn(store::run());
+
+    // compact the log once it grows too large or old
+    tokio::spawn(compaction::run());
+
     // this is used to simulate server crashes
     tokio::spawn(async {
         loop {
@@ -1,22 +1,19 @@
-use crate::types::{KeyValue, Key, Value};
+use crate::types::{KeyValue, Key, Value, LeaseId, TxnRequest, CASCondition, Session};
 use crate::snapshot::OPSnapshot;
+use crate::storage::{self, OmniPaxosStorage};
+use crate::store::StoreState;
+use crate::error::{Error, Result};
 
-use omnipaxos_core::{omni_paxos::{OmniPaxos, OmniPaxosConfig}, messages::Message, util::{NodeId, LogEntry}};
+use omnipaxos_core::{omni_paxos::{OmniPaxos, OmniPaxosConfig, ReconfigurationRequest}, messages::Message, util::NodeId, storage::StopSign, ballot_leader_election::Ballot};
 
-#[cfg(not(feature = "crash_recovery"))]
-use omnipaxos_storage::memory_storage::*;
-#[cfg(feature = "crash_recovery")]
-use omnipaxos_storage::persistent_storage::*;
-use serde::{Serialize, Deserialize};
-use tokio::time;
-use axum::extract::Json;
-use reqwest;
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use tokio::{time, sync::Notify, task::JoinSet};
+use axum::extract::{Json, Query};
 use std::{env, sync::{Arc, Mutex}, collections::HashMap};
+#[cfg(feature = "pl")]
+use std::{collections::{BTreeSet, VecDeque}, sync::atomic::{AtomicU64, Ordering}};
 
 
-#[cfg(all(feature = "pl", feature = "crash_recovery"))]
-compile_error!("features `pl` and `crash_recovery` are mutually exclusive");
-
 lazy_static! {
     static ref OUTGOING_INTERVAL: u64 = if let Ok(var) = env::var("OUTGOING_INTERVAL") {
         var.parse().expect("OUTGOING_INTERVAL must be u64 in millis")
@@ -30,6 +27,13 @@ lazy_static! {
         100
     };
 
+    /// how far clocks may drift apart during one leader lease, which lasts ELECTION_TIMEOUT minus this
+    static ref CLOCK_DRIFT: u64 = if let Ok(var) = env::var("CLOCK_DRIFT") {
+        var.parse().expect("CLOCK_DRIFT must be u64 in millis")
+    } else {
+        20
+    };
+
     static ref PID: NodeId = if let Ok(var) = env::var("PID") {
         let x = var.parse().expect("PIDs must be u64");
         if x == 0 { panic!("PIDs cannot be 0") } else { x }
@@ -37,6 +41,20 @@ lazy_static! {
         panic!("missing PID env var")
     };
 
+    /// how long an admin request waits for a configuration change to be decided
+    static ref RECONFIGURE_TIMEOUT: u64 = if let Ok(var) = env::var("RECONFIGURE_TIMEOUT") {
+        var.parse().expect("RECONFIGURE_TIMEOUT must be u64 in millis")
+    } else {
+        10000
+    };
+
+    /// nodes joining a running cluster start in the configuration that added them
+    static ref CONFIGURATION_ID: u32 = if let Ok(var) = env::var("CONFIGURATION_ID") {
+        var.parse().expect("CONFIGURATION_ID must be u32")
+    } else {
+        1
+    };
+
     static ref PEERS: Vec<NodeId> = if let Ok(var) = env::var("PEERS") {
         var.split(",").map(|s| {
             let x = s.parse().expect("PIDs must be u64");
@@ -51,60 +69,78 @@ lazy_static! {
     } else {
         panic!("missing PEERS env var")
     };
+
+    /// signalled whenever omnipaxos handled a message, so the applier does not have to poll
+    static ref PROGRESS: Notify = Notify::new();
+}
+
+#[cfg(feature = "pl")]
+lazy_static! {
+    /// how many unacknowledged messages are sent to a peer at once
+    static ref PL_WINDOW: usize = if let Ok(var) = env::var("PL_WINDOW") {
+        var.parse().expect("PL_WINDOW must be usize")
+    } else {
+        32
+    };
+
+    /// the longest wait before an unreachable peer is sent to again
+    static ref PL_MAX_BACKOFF: u64 = if let Ok(var) = env::var("PL_MAX_BACKOFF") {
+        var.parse().expect("PL_MAX_BACKOFF must be u64 in millis")
+    } else {
+        1000
+    };
 }
 
 static mut INSTANCE: Option<Arc<Mutex<RSM>>> = None;
 static mut COMMAND_COUNTER: Option<Arc<Mutex<u64>>> = None;
 #[cfg(feature = "pl")]
-static mut OUT_MSG_COUNTER: Option<Arc<Mutex<u64>>> = None;
+static mut OUT_MSG_COUNTER: Option<Arc<Mutex<HashMap<NodeId, u64>>>> = None;
+#[cfg(feature = "pl")]
+static PL_OUTGOING: PlFile = PlFile::new("pl_outgoing");
+#[cfg(feature = "pl")]
+static PL_DELIVERED: PlFile = PlFile::new("pl_delivered");
 
-/// Generates a globally unique id for an RSMCommand
-#[cfg(not(feature = "crash_recovery"))]
-fn generate_cmd_id() -> (u64, u64) {
-    unsafe {
-        let unlocked = if let Some(ref x) = COMMAND_COUNTER {
-            x.clone()
-        } else {
-            let x = Arc::new(Mutex::new(0));
-            COMMAND_COUNTER = Some(x.clone());
-            x
-        };
-        let mut counter = unlocked.lock().unwrap();
-        *counter += 1;
-        (*PID, *counter)
-    }
-}
+/// how many sequence ids past its high-water mark a receiver keeps track of, messages further ahead are retransmitted later
+#[cfg(feature = "pl")]
+const REORDER_WINDOW: u64 = 64;
 
-/// Generates a locally unique id for use as a sequence_id on SequencePaxos Messages
+/// Generates the next sequence_id for a SequencePaxos message to `receiver`,
+/// every receiver gets consecutive ids starting at 1
 #[cfg(feature = "pl")]
-fn generate_sequence_id() -> u64 {
+fn generate_sequence_id(receiver: NodeId) -> u64 {
     unsafe {
         let unlocked = if let Some(ref x) = OUT_MSG_COUNTER {
             x.clone()
         } else {
-            let x = Arc::new(Mutex::new(0));
+            // sequence ids must not repeat after a restart, or peers would drop new messages as duplicates
+            let (sequence_ids, _) = load_pl::<(HashMap<NodeId, u64>, HashMap<NodeId, OutgoingQueue>)>("pl_outgoing");
+            let x = Arc::new(Mutex::new(sequence_ids));
             OUT_MSG_COUNTER = Some(x.clone());
             x
         };
-        let mut counter = unlocked.lock().unwrap();
+        let mut counters = unlocked.lock().unwrap();
+        let counter = counters.entry(receiver).or_insert(0);
         *counter += 1;
         *counter
     }
 }
 
 /// Generates a globally unique id for an RSMCommand
-#[cfg(feature = "crash_recovery")]
 fn generate_cmd_id() -> (u64, u64) {
+    let path_a = storage::data_path("etcd_cmd_id_a");
+    let path_b = storage::data_path("etcd_cmd_id_b");
     unsafe {
         let unlocked = if let Some(ref x) = COMMAND_COUNTER {
             x.clone()
         } else {
-            let val = if let Ok(texta) = std::fs::read_to_string("/data/etcd_cmd_id_a") {
+            let val = if !storage::persistent() {
+                0
+            } else if let Ok(texta) = std::fs::read_to_string(&path_a) {
                 if let Ok(n) = texta.parse() {
                     n
                 } else {
-                    if let Ok(textb) = std::fs::read_to_string("/data/etcd_cmd_id_b") {
-                        if let Ok(n) = textb.parse() { n } else { 0 }
+                    if let Ok(textb) = std::fs::read_to_string(&path_b) {
+                        textb.parse().unwrap_or_default()
                     } else { 0 }
                 }
             } else { 0 };
@@ -114,35 +150,169 @@ fn generate_cmd_id() -> (u64, u64) {
         };
         let mut counter = unlocked.lock().unwrap();
         *counter += 1;
-        // we write redundant files, in case we crash while writing one of them
-        std::fs::write("/data/etcd_cmd_id_a", counter.to_string()).unwrap();
-        std::fs::write("/data/etcd_cmd_id_b", counter.to_string()).unwrap();
+        if storage::persistent() {
+            // we write redundant files, in case we crash while writing one of them
+            std::fs::write(&path_a, counter.to_string()).unwrap();
+            std::fs::write(&path_b, counter.to_string()).unwrap();
+        }
         (*PID, *counter)
     }
 }
 
+/// Loads perfect link state persisted in the data directory, nodes without persistent storage start fresh
+#[cfg(feature = "pl")]
+fn load_pl<T: DeserializeOwned + Default>(name: &str) -> T {
+    if !storage::persistent() {
+        return T::default()
+    }
+    std::fs::read(storage::data_path(name)).ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+/// A file of perfect link state, written without holding the RSM lock. The state is serialized under the lock,
+/// and a write is skipped once a state serialized after it is on disk, so the file never goes back in time.
+#[cfg(feature = "pl")]
+struct PlFile {
+    name: &'static str,
+    /// generation of the last serialized state
+    taken: AtomicU64,
+    /// generation of the state on disk
+    durable: AtomicU64,
+    /// held while the file is written
+    writing: Mutex<()>,
+}
+
+#[cfg(feature = "pl")]
+impl PlFile {
+    const fn new(name: &'static str) -> Self {
+        Self{ name, taken: AtomicU64::new(0), durable: AtomicU64::new(0), writing: Mutex::new(()) }
+    }
+
+    /// Whether the last serialized state is on disk, it is not after a failed write
+    fn is_durable(&self) -> bool {
+        self.durable.load(Ordering::SeqCst) >= self.taken.load(Ordering::SeqCst)
+    }
+
+    /// Serializes a state, under the RSM lock so generations follow the order of the states
+    fn take<T: Serialize>(&self, state: &T) -> Result<(u64, Vec<u8>)> {
+        let bytes = serde_json::to_vec(state).map_err(|err| Error::Storage(err.to_string()))?;
+        Ok((self.taken.fetch_add(1, Ordering::SeqCst) + 1, bytes))
+    }
+
+    /// Writes a serialized state on a blocking thread, unless a later one is on disk already
+    async fn write(&'static self, (generation, bytes): (u64, Vec<u8>)) -> Result<()> {
+        tokio::task::spawn_blocking(move || {
+            let _writing = self.writing.lock().unwrap();
+            if self.durable.load(Ordering::SeqCst) >= generation {
+                return Ok(())
+            }
+            storage::write_atomically(self.name, &bytes)?;
+            self.durable.store(generation, Ordering::SeqCst);
+            Ok(())
+        }).await.unwrap_or_else(|err| Err(Error::Storage(err.to_string())))
+    }
+}
+
+/// Serializes the sequence counters together with the messages that were not acknowledged yet
+#[cfg(feature = "pl")]
+fn take_outgoing(rsm: &RSM) -> Result<(u64, Vec<u8>)> {
+    let sequence_ids = unsafe {
+        if let Some(ref x) = OUT_MSG_COUNTER {
+            x.lock().unwrap().clone()
+        } else {
+            HashMap::default()
+        }
+    };
+    let queues: HashMap<&NodeId, &OutgoingQueue> = rsm.links.iter().map(|(pid, link)| (pid, &link.queue)).collect();
+    PL_OUTGOING.take(&(sequence_ids, queues))
+}
+
+/// Which messages of a sender were delivered, in constant memory
+#[cfg(feature = "pl")]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Delivered {
+    /// every message up to this sequence id has been delivered, it is sent back as the ack
+    high_water_mark: u64,
+    /// messages delivered out of order, within REORDER_WINDOW past the high-water mark
+    ahead: BTreeSet<u64>,
+}
+
+#[cfg(feature = "pl")]
+impl Delivered {
+    /// Records the delivery of a message, false if it was delivered before or is too far ahead to be tracked
+    fn accept(&mut self, sequence_id: u64) -> bool {
+        // a receiver that lost its state picks up wherever the sender is
+        if self.high_water_mark == 0 && self.ahead.is_empty() {
+            self.high_water_mark = sequence_id - 1;
+        }
+        if sequence_id <= self.high_water_mark || sequence_id > self.high_water_mark + REORDER_WINDOW || !self.ahead.insert(sequence_id) {
+            return false
+        }
+        while self.ahead.remove(&(self.high_water_mark + 1)) {
+            self.high_water_mark += 1;
+        }
+        true
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum RSMCommand{
-    Put(((u64, u64), KeyValue)),
+    Put(((u64, u64), KeyValue, Option<LeaseId>)),
     LinearizableRead((u64, u64)),
-    CAS(((u64, u64), KeyValue, Value)),
+    CAS(((u64, u64), KeyValue, CASCondition)),
     Delete(((u64, u64), Key)),
     Clear((u64, u64)),
+    /// grants a lease with a TTL in seconds, the lease id is the revision of this command
+    LeaseGrant(((u64, u64), u64)),
+    LeaseKeepAlive(((u64, u64), LeaseId)),
+    /// revokes a lease and deletes all of its keys, also used by the leader to expire leases
+    LeaseRevoke(((u64, u64), LeaseId)),
+    Txn(((u64, u64), TxnRequest)),
+    /// first entry of every new configuration, carries the store over from the previous one
+    Migrate(((u64, u64), StoreState)),
+    /// a command of a client session, it is only applied if its sequence number is new
+    Session((Session, Box<RSMCommand>)),
+    /// forgets idle client sessions, proposed by the leader
+    ExpireSessions(((u64, u64), Vec<String>)),
 }
 
 impl RSMCommand {
     pub fn get_id(&self) -> (u64, u64) {
         match self {
-            Self::Put((id, _)) => *id,
+            Self::Put((id, _, _)) => *id,
             Self::Delete((id, _)) => *id,
             Self::CAS((id, _, _)) => *id,
             Self::LinearizableRead(id) => *id,
             Self::Clear(id) => *id,
+            Self::LeaseGrant((id, _)) => *id,
+            Self::LeaseKeepAlive((id, _)) => *id,
+            Self::LeaseRevoke((id, _)) => *id,
+            Self::Txn((id, _)) => *id,
+            Self::Migrate((id, _)) => *id,
+            Self::Session((_, cmd)) => cmd.get_id(),
+            Self::ExpireSessions((id, _)) => *id,
+        }
+    }
+
+    /// The command itself, without its client session
+    pub fn inner(&self) -> &Self {
+        match self {
+            Self::Session((_, cmd)) => cmd.inner(),
+            _ => self,
+        }
+    }
+
+    /// Attaches a command to a client session
+    pub fn with_session(self, session: Option<Session>) -> Self {
+        match session {
+            Some(session) => Self::Session((session, Box::new(self))),
+            None => self,
         }
     }
 
-    pub fn new_put(kv: KeyValue) -> Self {
-        Self::Put((generate_cmd_id(), kv))
+    pub fn new_put(kv: KeyValue, lease: Option<LeaseId>) -> Self {
+        Self::Put((generate_cmd_id(), kv, lease))
     }
 
     pub fn new_linearizable_read() -> Self {
@@ -157,27 +327,135 @@ impl RSMCommand {
         Self::Clear(generate_cmd_id())
     }
 
-    pub fn new_cas(key: Key, new_v: Value, exp_v: Value) -> Self {
-        Self::CAS((generate_cmd_id(), KeyValue{key, value: new_v}, exp_v))
+    pub fn new_cas(key: Key, new_v: Value, condition: CASCondition) -> Self {
+        Self::CAS((generate_cmd_id(), KeyValue{key, value: new_v}, condition))
+    }
+
+    pub fn new_lease_grant(ttl: u64) -> Self {
+        Self::LeaseGrant((generate_cmd_id(), ttl))
+    }
+
+    pub fn new_lease_keep_alive(lease: LeaseId) -> Self {
+        Self::LeaseKeepAlive((generate_cmd_id(), lease))
+    }
+
+    pub fn new_lease_revoke(lease: LeaseId) -> Self {
+        Self::LeaseRevoke((generate_cmd_id(), lease))
+    }
+
+    pub fn new_txn(txn: TxnRequest) -> Self {
+        Self::Txn((generate_cmd_id(), txn))
+    }
+
+    pub fn new_expire_sessions(sessions: Vec<String>) -> Self {
+        Self::ExpireSessions((generate_cmd_id(), sessions))
     }
 }
 
 type OmniPaxosMessage = Message<RSMCommand, OPSnapshot>;
-#[cfg(not(feature = "crash_recovery"))]
-type OmniPaxosStorage = MemoryStorage<RSMCommand, OPSnapshot>;
-#[cfg(feature = "crash_recovery")]
-type OmniPaxosStorage = PersistentStorage<RSMCommand, OPSnapshot>;
+/// SequencePaxos messages to a peer that were not acknowledged yet, with their sequence id and configuration
+#[cfg(feature = "pl")]
+type OutgoingQueue = VecDeque<(u64, u32, OmniPaxosMessage)>;
+/// Messages that are posted to a peer in one request, with their sequence id and configuration
+#[cfg(feature = "pl")]
+type Batch = Vec<(u64, u32, OmniPaxosMessage)>;
+
+/// The sending side of the perfect link to a peer
+#[cfg(feature = "pl")]
+#[derive(Default)]
+struct Link {
+    queue: OutgoingQueue,
+    /// a batch is on its way to the peer
+    sending: bool,
+    /// heartbeats are on their way to the peer
+    heartbeating: bool,
+    /// sends that failed in a row
+    failures: u32,
+    /// an unreachable peer is not sent to again before this
+    retry_at: Option<time::Instant>,
+}
+
+#[cfg(feature = "pl")]
+impl Link {
+    /// Backs off from a peer that could not be reached, until a send to it works again
+    fn reached(&mut self, reached: bool) {
+        if reached {
+            self.failures = 0;
+            self.retry_at = None;
+        } else {
+            self.failures += 1;
+            let backoff = (*OUTGOING_INTERVAL << self.failures.min(16)).min(*PL_MAX_BACKOFF);
+            self.retry_at = Some(time::Instant::now() + time::Duration::from_millis(backoff));
+        }
+    }
+
+    fn backing_off(&self, now: time::Instant) -> bool {
+        self.retry_at.is_some_and(|at| at > now)
+    }
+}
+
 type OmniPaxosType = OmniPaxos<RSMCommand, OPSnapshot, OmniPaxosStorage>;
 
+/// A configuration that was replaced through a StopSign. It keeps running,
+/// so peers that have not decided the StopSign yet can still catch up.
+struct Retired {
+    config_id: u32,
+    omnipaxos: OmniPaxosType,
+}
+
+/// The store of the previous configuration, until it is decided as the first entry of the current one
+struct CarryOver {
+    state: StoreState,
+    /// nodes of the previous configuration, which all have the state
+    old_nodes: Vec<NodeId>,
+    /// the leader the state was last proposed to
+    proposed_to: Option<NodeId>,
+}
+
 pub struct RSM {
     pub omnipaxos: OmniPaxosType,
+    pub config_id: u32,
+    /// all nodes of the current configuration, including this one
+    pub nodes: Vec<NodeId>,
+    /// global log index at which the log of the current configuration starts
+    pub log_offset: u64,
+    /// proposals are only accepted once the store has been carried over into the current configuration
+    pub accepting: bool,
+    /// set if this node is not part of the latest configuration
+    pub removed: bool,
+    retired: Option<Retired>,
+    carry_over: Option<CarryOver>,
     addrs: HashMap<NodeId, String>,
+    /// the ballot this node has promised in the current configuration
+    promise: Arc<Mutex<Ballot>>,
+    /// configuration and start of the last heartbeat round a quorum of followers acknowledged this node as leader in
+    lease: Option<(u32, time::Instant)>,
+    renewing_lease: bool,
+    /// until when this node helps elect no leader but the given one, which may hold a lease. None holds back every leader
+    lease_grant: (Option<NodeId>, time::Instant),
+    /// messages of other leaders that arrived while a lease grant was running, delivered once it ran out or lost on a crash
+    deferred: Vec<(u32, OmniPaxosMessage)>,
+    /// when this node last received a message from the leader of the current configuration
+    leader_contact: Option<time::Instant>,
     #[cfg(not(feature = "pl"))]
     connected: HashMap<NodeId, bool>,
     #[cfg(feature = "pl")]
-    outgoing_buffer: Vec<(u64, String, OmniPaxosMessage)>,
+    links: HashMap<NodeId, Link>,
     #[cfg(feature = "pl")]
-    delivered_msgs: HashMap<NodeId, Vec<u64>>,
+    delivered_msgs: HashMap<NodeId, Delivered>,
+}
+
+/// Builds the OmniPaxos instance of a configuration, together with a handle on the ballot it promised
+fn build_omnipaxos(configuration_id: u32, peers: Vec<NodeId>) -> (OmniPaxosType, Arc<Mutex<Ballot>>) {
+    let op_config = OmniPaxosConfig{
+        pid: *PID,
+        configuration_id,
+        peers,
+        ..Default::default()
+    };
+    let storage = OmniPaxosStorage::open(configuration_id);
+    let promise = storage.promise();
+    (op_config.build(storage), promise)
 }
 
 impl RSM {
@@ -187,46 +465,65 @@ impl RSM {
             if let Some(ref rsm) = INSTANCE {
                 rsm.clone()
             } else {
-                let op_config = OmniPaxosConfig{
-                    pid: *PID,
-                    configuration_id: 1,
-                    peers: PEERS.clone(),
-                    ..Default::default()
-                };
-
-                #[cfg(not(feature = "crash_recovery"))]
-                let omnipaxos = op_config.build(MemoryStorage::default());
-                #[cfg(feature = "crash_recovery")]
-                let omnipaxos = {
-                    let mut storage_config = PersistentStorageConfig::default();
-                    storage_config.set_path("/data/op_storage".to_string());
-                    let storage = PersistentStorage::open(storage_config);
-                    op_config.build(storage)
-                };
+                let (omnipaxos, promise) = build_omnipaxos(*CONFIGURATION_ID, PEERS.clone());
+                let mut nodes = PEERS.clone();
+                nodes.push(*PID);
+                nodes.sort();
 
                 let mut addrs = HashMap::default();
                 #[cfg(not(feature = "pl"))]
                 let mut connected = HashMap::default();
+                // messages that were delivered or not acknowledged before a restart are remembered
                 #[cfg(feature = "pl")]
-                let mut delivered_msgs = HashMap::default();
+                let mut delivered_msgs: HashMap<NodeId, Delivered> = load_pl("pl_delivered");
                 for i in 0..PEERS.len() {
                     addrs.insert(PEERS[i], PEER_DOMAINS[i].clone());
                     #[cfg(not(feature = "pl"))]
                     connected.insert(PEERS[i], false);
                     #[cfg(feature = "pl")]
-                    delivered_msgs.insert(PEERS[i], vec![]);
+                    delivered_msgs.entry(PEERS[i]).or_default();
                 }
                 #[cfg(feature = "pl")]
                 let rsm = Arc::new(Mutex::new(RSM{
                     omnipaxos,
+                    config_id: *CONFIGURATION_ID,
+                    nodes,
+                    log_offset: 0,
+                    // a joining node has no state until it has applied the first entry of its configuration
+                    accepting: *CONFIGURATION_ID == 1,
+                    removed: false,
+                    retired: None,
+                    carry_over: None,
                     addrs,
-                    outgoing_buffer: vec![],
+                    promise,
+                    lease: None,
+                    renewing_lease: false,
+                    // this node may have granted a lease right before it restarted
+                    lease_grant: (None, time::Instant::now() + time::Duration::from_millis(*ELECTION_TIMEOUT)),
+                    deferred: vec![],
+                    leader_contact: None,
+                    links: load_pl::<(HashMap<NodeId, u64>, HashMap<NodeId, OutgoingQueue>)>("pl_outgoing").1.into_iter()
+                        .map(|(pid, queue)| (pid, Link{ queue, ..Default::default() }))
+                        .collect(),
                     delivered_msgs,
                 }));
                 #[cfg(not(feature = "pl"))]
                 let rsm = Arc::new(Mutex::new(RSM{
                     omnipaxos,
+                    config_id: *CONFIGURATION_ID,
+                    nodes,
+                    log_offset: 0,
+                    accepting: *CONFIGURATION_ID == 1,
+                    removed: false,
+                    retired: None,
+                    carry_over: None,
                     addrs,
+                    promise,
+                    lease: None,
+                    renewing_lease: false,
+                    lease_grant: (None, time::Instant::now() + time::Duration::from_millis(*ELECTION_TIMEOUT)),
+                    deferred: vec![],
+                    leader_contact: None,
                     connected,
                 }));
                 INSTANCE = Some(rsm.clone());
@@ -234,52 +531,369 @@ impl RSM {
             }
         }
     }
+
+    /// The end of this node's log, as a global index
+    fn log_end(&self) -> u64 {
+        let decided_idx = self.omnipaxos.get_decided_idx();
+        let undecided = self.omnipaxos.read_entries(decided_idx..).map_or(0, |entries| entries.len() as u64);
+        self.log_offset + decided_idx + undecided
+    }
+
+    /// Whether this node leads and no other node can have been elected since the lease started.
+    /// A quorum of followers helps elect no other leader for an election timeout after acknowledging the lease.
+    fn holds_lease(&self) -> bool {
+        match self.lease {
+            Some((config_id, start)) => config_id == self.config_id
+                && self.omnipaxos.get_current_leader() == Some(*PID)
+                && time::Instant::now() + time::Duration::from_millis(*CLOCK_DRIFT) < start + time::Duration::from_millis(*ELECTION_TIMEOUT),
+            None => false,
+        }
+    }
+
+    /// Switches to the configuration of a decided StopSign. `log_offset` is the global log index right
+    /// after the StopSign, `state` becomes the first entry of the new configuration.
+    pub fn migrate(&mut self, ss: StopSign, log_offset: u64, state: StoreState) {
+        if let Some(ref metadata) = ss.metadata {
+            if let Ok(addrs) = serde_json::from_slice::<HashMap<NodeId, String>>(metadata) {
+                self.addrs.extend(addrs.into_iter().filter(|(pid, _)| *pid != *PID));
+            }
+        }
+        self.accepting = false;
+        self.leader_contact = None;
+        if !ss.nodes.contains(&*PID) {
+            println!("removed from the cluster in configuration {}", ss.config_id);
+            self.removed = true;
+            return
+        }
+        let peers: Vec<NodeId> = ss.nodes.iter().filter(|pid| **pid != *PID).cloned().collect();
+        for pid in peers.iter() {
+            #[cfg(not(feature = "pl"))]
+            self.connected.entry(*pid).or_insert(false);
+            #[cfg(feature = "pl")]
+            self.delivered_msgs.entry(*pid).or_default();
+        }
+        let (omnipaxos, promise) = build_omnipaxos(ss.config_id, peers);
+        let old = std::mem::replace(&mut self.omnipaxos, omnipaxos);
+        self.promise = promise;
+        self.retired = Some(Retired{ config_id: self.config_id, omnipaxos: old });
+        self.config_id = ss.config_id;
+        let old_nodes = std::mem::replace(&mut self.nodes, ss.nodes);
+        // only peers of this configuration and of the retired one, which may still have to catch up, are linked
+        #[cfg(feature = "pl")]
+        self.links.retain(|pid, _| self.nodes.contains(pid) || old_nodes.contains(pid));
+        #[cfg(feature = "pl")]
+        self.delivered_msgs.retain(|pid, _| self.nodes.contains(pid) || old_nodes.contains(pid));
+        self.log_offset = log_offset;
+        self.carry_over = Some(CarryOver{ state, old_nodes, proposed_to: None });
+        self.propose_carry_over();
+    }
+
+    /// Proposes the carried over store as the first entry of the current configuration. Only its leader proposes it,
+    /// or the lowest node of the previous configuration if the leader is new and has no state, so the log gets a
+    /// single copy. A proposal may be lost when the leader changes, so it is proposed again to every new leader.
+    fn propose_carry_over(&mut self) {
+        if self.accepting {
+            self.carry_over = None;
+            return
+        }
+        let leader = match self.omnipaxos.get_current_leader() {
+            Some(leader) => leader,
+            None => return,
+        };
+        let proposer = match self.carry_over {
+            Some(ref carry_over) if carry_over.proposed_to != Some(leader) => if carry_over.old_nodes.contains(&leader) {
+                leader
+            } else {
+                match self.nodes.iter().filter(|pid| carry_over.old_nodes.contains(pid)).min() {
+                    Some(pid) => *pid,
+                    None => return,
+                }
+            },
+            _ => return,
+        };
+        if proposer != *PID {
+            return
+        }
+        let carry_over = self.carry_over.as_mut().expect("checked above");
+        if self.omnipaxos.append(RSMCommand::Migrate((generate_cmd_id(), carry_over.state.clone()))).is_ok() {
+            carry_over.proposed_to = Some(leader);
+        }
+    }
+
+    /// Whether this node has granted a lease to a leader other than `pid` that may still hold it
+    fn granted_to_other(&self, pid: NodeId) -> bool {
+        let (leader, until) = self.lease_grant;
+        leader != Some(pid) && time::Instant::now() < until
+    }
+
+    /// Delivers the messages that were held back by a lease grant that ran out
+    fn release_deferred(&mut self) {
+        if !self.deferred.is_empty() && time::Instant::now() >= self.lease_grant.1 {
+            for (config_id, msg) in std::mem::take(&mut self.deferred) {
+                self.deliver(config_id, msg);
+            }
+        }
+    }
+
+    /// Delivers a message to the instance of the configuration it was sent in, messages for unknown configurations are dropped
+    fn deliver(&mut self, config_id: u32, msg: OmniPaxosMessage) {
+        self.release_deferred();
+        if config_id == self.config_id {
+            // other leaders have to wait until the grant ran out, so this node cannot promise them anything before
+            if let OmniPaxosMessage::SequencePaxos(_) = msg {
+                if self.granted_to_other(msg.get_sender()) {
+                    self.deferred.push((config_id, msg));
+                    return
+                }
+            }
+            if self.omnipaxos.get_current_leader() == Some(msg.get_sender()) {
+                self.leader_contact = Some(time::Instant::now());
+            }
+            self.omnipaxos.handle_incoming(msg);
+        } else if let Some(ref mut retired) = self.retired {
+            if retired.config_id == config_id {
+                retired.omnipaxos.handle_incoming(msg);
+            }
+        }
+        PROGRESS.notify_one();
+    }
 }
 
-/// Appends an entry and waits until it is decided
-/// returns the index of the decided entry on success
-pub async fn append(cmd: RSMCommand) -> Result<u64, ()> {
-    let start_decided_idx;
-    {
+/// Whether this node currently considers itself the leader
+pub fn is_leader() -> bool {
+    RSM::instance().lock().unwrap().omnipaxos.get_current_leader() == Some(*PID)
+}
+
+/// The current leader and its address, which is unknown if this node leads
+pub fn leader() -> Option<(NodeId, Option<String>)> {
+    let unlocked = RSM::instance();
+    let rsm = unlocked.lock().unwrap();
+    let leader = rsm.omnipaxos.get_current_leader()?;
+    Some((leader, rsm.addrs.get(&leader).cloned()))
+}
+
+/// Global log index up to which entries have been decided, as far as this node knows
+pub fn decided_index() -> u64 {
+    let unlocked = RSM::instance();
+    let rsm = unlocked.lock().unwrap();
+    rsm.log_offset + rsm.omnipaxos.get_decided_idx()
+}
+
+/// The decided index of the leader, which followers have to ask for
+pub async fn leader_decided_index() -> Result<u64> {
+    match leader().ok_or(Error::NoLeader)? {
+        (pid, _) if pid == *PID => Ok(decided_index()),
+        (_, Some(addr)) => {
+            let resp = reqwest::Client::new().get(format!("http://{}/decided_index", addr)).send().await
+                .map_err(|err| Error::Forward(err.to_string()))?;
+            resp.json().await.map_err(|err| Error::Forward(err.to_string()))
+        },
+        (_, None) => Err(Error::NoLeader),
+    }
+}
+
+/// Addresses of all peers of the current configuration
+pub fn peer_addrs() -> Vec<String> {
+    let unlocked = RSM::instance();
+    let rsm = unlocked.lock().unwrap();
+    rsm.nodes.iter().filter_map(|pid| rsm.addrs.get(pid).cloned()).collect()
+}
+
+/// Sends a GET request for `path` to all peers of the current configuration,
+/// the answers are in no particular order and `None` for peers that could not be reached
+pub async fn query_peers<T: DeserializeOwned + Send + 'static>(path: &str) -> Vec<Option<T>> {
+    let mut requests = JoinSet::new();
+    for addr in peer_addrs() {
+        let url = format!("http://{}{}", addr, path);
+        requests.spawn(async move {
+            let resp = reqwest::Client::new().get(url).send().await.ok()?;
+            if !resp.status().is_success() {
+                return None
+            }
+            resp.json::<T>().await.ok()
+        });
+    }
+    let mut answers = vec![];
+    while let Some(answer) = requests.join_next().await {
+        answers.push(answer.ok().flatten());
+    }
+    answers
+}
+
+/// How long ago this node last heard from the leader, zero if it leads itself
+pub fn since_leader_contact() -> Option<time::Duration> {
+    let unlocked = RSM::instance();
+    let rsm = unlocked.lock().unwrap();
+    match rsm.omnipaxos.get_current_leader() {
+        Some(leader) if leader == *PID => Some(time::Duration::ZERO),
+        Some(_) => rsm.leader_contact.map(|contact| contact.elapsed()),
+        None => None,
+    }
+}
+
+/// This node's id
+pub fn pid() -> NodeId {
+    *PID
+}
+
+/// Appends an entry to the log, the store's applier tells proposers when it is decided
+pub fn propose(cmd: RSMCommand) -> Result<()> {
+    let unlocked = RSM::instance();
+    let mut rsm = unlocked.lock().unwrap();
+    // a new configuration first has to carry over the store
+    if !rsm.accepting {
+        return Err(Error::ProposalDropped);
+    }
+    if rsm.omnipaxos.append(cmd).is_err() {
+        return Err(if rsm.omnipaxos.get_current_leader().is_none() { Error::NoLeader } else { Error::ProposalDropped });
+    }
+    Ok(())
+}
+
+/// Waits until new messages have been handled, after which more entries may be decided
+pub async fn progress() {
+    PROGRESS.notified().await
+}
+
+/// The current configuration id and its nodes
+pub fn config() -> (u32, Vec<NodeId>) {
+    let unlocked = RSM::instance();
+    let rsm = unlocked.lock().unwrap();
+    (rsm.config_id, rsm.nodes.clone())
+}
+
+/// Proposes a new configuration consisting of `nodes`. `addrs` are the addresses of nodes that are new
+/// to the cluster, they are shared with all nodes through the StopSign.
+/// Waits until this node has moved on from its current configuration.
+pub async fn reconfigure(nodes: Vec<NodeId>, addrs: HashMap<NodeId, String>) -> Result<(u32, Vec<NodeId>)> {
+    let config_id = {
         let unlocked = RSM::instance();
         let mut rsm = unlocked.lock().unwrap();
-        start_decided_idx = rsm.omnipaxos.get_decided_idx();
-        if let Err(_) = rsm.omnipaxos.append(cmd.clone()) {
-            return Err(());
+        let metadata = serde_json::to_vec(&addrs).ok();
+        if rsm.omnipaxos.reconfigure(ReconfigurationRequest::with(nodes, metadata)).is_err() {
+            return Err(if rsm.omnipaxos.get_current_leader().is_none() { Error::NoLeader } else { Error::ProposalDropped });
+        }
+        rsm.config_id
+    };
+    let deadline = time::Instant::now() + time::Duration::from_millis(*RECONFIGURE_TIMEOUT);
+    loop {
+        if time::Instant::now() >= deadline {
+            return Err(Error::Timeout);
+        }
+        time::sleep(time::Duration::from_millis(10)).await;
+        let unlocked = RSM::instance();
+        let rsm = unlocked.lock().unwrap();
+        if rsm.config_id != config_id || rsm.removed {
+            return Ok((rsm.config_id, rsm.nodes.clone()));
         }
     }
+}
 
-    // wait until decided
-    loop {
-        time::sleep(time::Duration::from_millis(1)).await;
-        if let Some(entries) = RSM::instance().lock().unwrap().omnipaxos.read_decided_suffix(start_decided_idx) {
-            for (i, entry) in entries.iter().enumerate() {
-                match entry {
-                    LogEntry::Decided(new) => {
-                        if new.get_id() == cmd.get_id() {
-                            return Ok(start_decided_idx+i as u64);
-                        }
-                    },
-                    _ => (),
-                }
+/// Global log index up to which a linearizable read has to wait. This is the end of the leader's log, because
+/// entries that were accepted by an earlier leader may not be decided in the leader's own ballot yet.
+/// The leader confirms with a quorum that it still leads, so no other node can have decided anything newer.
+/// Followers ask the leader for its read index.
+pub async fn read_index() -> Result<u64> {
+    match leader().ok_or(Error::NoLeader)? {
+        (pid, _) if pid == *PID => leader_read_index(false).await,
+        (_, Some(addr)) => {
+            let resp = reqwest::Client::new().get(format!("http://{}/read_index", addr)).send().await
+                .map_err(|err| Error::Forward(err.to_string()))?;
+            if !resp.status().is_success() {
+                return Err(Error::NoLeader)
             }
+            resp.json().await.map_err(|err| Error::Forward(err.to_string()))
+        },
+        (_, None) => Err(Error::NoLeader),
+    }
+}
+
+/// Confirms leadership with a quorum of the current configuration and returns the read index.
+/// A follower acknowledges the leader's ballot only while it has not promised a higher one,
+/// so no other leader can have been elected once a quorum did. With `lease` the leader only counts
+/// followers, which grant it a lease by acknowledging, and holds the lease once a quorum of them did.
+async fn leader_read_index(lease: bool) -> Result<u64> {
+    let start = time::Instant::now();
+    let (config_id, ballot, index, peers, quorum) = {
+        let unlocked = RSM::instance();
+        let rsm = unlocked.lock().unwrap();
+        if rsm.omnipaxos.get_current_leader() != Some(*PID) {
+            return Err(Error::NoLeader)
         }
+        let ballot = *rsm.promise.lock().unwrap();
+        let peers: Vec<String> = rsm.nodes.iter().filter_map(|pid| rsm.addrs.get(pid).cloned()).collect();
+        (rsm.config_id, ballot, rsm.log_end(), peers, rsm.nodes.len() / 2 + 1)
+    };
+    let mut heartbeats = JoinSet::new();
+    for addr in peers {
+        heartbeats.spawn(async move {
+            let url = format!("http://{}/heartbeat?config={}", addr, config_id);
+            let resp = reqwest::Client::new().get(url).send().await.ok()?;
+            resp.json::<Option<Ballot>>().await.ok()?
+        });
+    }
+    // this node counts towards the quorum, unless it takes a lease, because it may still promise other leaders
+    let mut acks = if lease { 0 } else { 1 };
+    while acks < quorum {
+        match heartbeats.join_next().await {
+            Some(Ok(Some(promise))) if promise == ballot => acks += 1,
+            Some(_) => (),
+            None => return Err(Error::NoLeader),
+        }
+    }
+    let unlocked = RSM::instance();
+    let mut rsm = unlocked.lock().unwrap();
+    if rsm.config_id != config_id || rsm.omnipaxos.get_current_leader() != Some(*PID) || *rsm.promise.lock().unwrap() != ballot {
+        return Err(Error::NoLeader)
+    }
+    if lease {
+        rsm.lease = Some((config_id, start));
+    }
+    Ok(index)
+}
+
+/// The read index of a leader that holds a valid lease, which needs no quorum round. Otherwise
+/// a lease renewal is started in the background, and the read has to take another path.
+pub fn lease_read_index() -> Option<u64> {
+    let unlocked = RSM::instance();
+    let mut rsm = unlocked.lock().unwrap();
+    if rsm.holds_lease() {
+        return Some(rsm.log_end())
     }
+    if rsm.omnipaxos.get_current_leader() == Some(*PID) && !rsm.renewing_lease {
+        rsm.renewing_lease = true;
+        tokio::spawn(async {
+            let _ = leader_read_index(true).await;
+            RSM::instance().lock().unwrap().renewing_lease = false;
+        });
+    }
+    None
+}
+
+/// Takes the outgoing messages of the current and the retired configuration, tagged with their configuration id
+fn outgoing_messages(rsm: &mut RSM) -> Vec<(u32, OmniPaxosMessage)> {
+    let config_id = rsm.config_id;
+    let mut msgs: Vec<(u32, OmniPaxosMessage)> = rsm.omnipaxos.outgoing_messages().into_iter().map(|msg| (config_id, msg)).collect();
+    if let Some(ref mut retired) = rsm.retired {
+        let config_id = retired.config_id;
+        msgs.extend(retired.omnipaxos.outgoing_messages().into_iter().map(|msg| (config_id, msg)));
+    }
+    msgs
 }
 
 #[cfg(not(feature = "pl"))]
 async fn send_outgoing_msgs() {
-    let messages: Vec<(OmniPaxosMessage, String)> = { // open a new scope, so we can drop the lock on RSM asap
+    let messages: Vec<(OmniPaxosMessage, String, u32)> = { // open a new scope, so we can drop the lock on RSM asap
         let unlocked = RSM::instance();
         let mut rsm = unlocked.lock().unwrap();
-        let msgs = rsm.omnipaxos.outgoing_messages();
-        msgs.into_iter().map(|msg| {
+        let msgs = outgoing_messages(&mut rsm);
+        msgs.into_iter().filter_map(|(config_id, msg)| {
             let receiver_id = msg.get_receiver();
-            (msg, rsm.addrs.get(&receiver_id).unwrap().to_owned())
+            rsm.addrs.get(&receiver_id).map(|addr| (msg, addr.to_owned(), config_id))
         }).collect()
     };
     for msg in messages {
-        let url = format!("http://{}/omnipaxos", msg.1);
+        let url = format!("http://{}/omnipaxos?config={}", msg.1, msg.2);
         match reqwest::Client::new().post(url).json(&msg.0).send().await {
             Ok(_) => {
                 let unlocked = RSM::instance();
@@ -299,48 +913,143 @@ async fn send_outgoing_msgs() {
 
 #[cfg(feature = "pl")]
 async fn send_outgoing_msgs() {
-    let mut ble_msg_buf = vec![];
+    let mut ble_msgs: HashMap<NodeId, (String, Batch)> = HashMap::new();
+    let mut batches = vec![];
 
-    { // open a new scope, so we can drop the lock on RSM before we start actually sending messages
+    let outgoing = { // open a new scope, so we can drop the lock on RSM before we start actually sending messages
         let unlocked = RSM::instance();
-        let mut rsm = unlocked.lock().unwrap();
-        let msgs = rsm.omnipaxos.outgoing_messages();
-        msgs.into_iter().for_each(|msg| {
+        let mut guard = unlocked.lock().unwrap();
+        let rsm = &mut *guard;
+        let mut queued = false;
+        let now = time::Instant::now();
+        for (config_id, msg) in outgoing_messages(rsm) {
             let receiver_id = msg.get_receiver();
-            let addr = rsm.addrs.get(&receiver_id).unwrap().to_owned();
+            let addr = match rsm.addrs.get(&receiver_id) {
+                Some(addr) => addr.to_owned(),
+                None => continue,
+            };
             match msg {
                 OmniPaxosMessage::SequencePaxos(_) => {
-                    let sequence_id = generate_sequence_id();
-                    rsm.outgoing_buffer.push((sequence_id, addr, msg));
+                    let sequence_id = generate_sequence_id(receiver_id);
+                    rsm.links.entry(receiver_id).or_default().queue.push_back((sequence_id, config_id, msg));
+                    queued = true;
                 },
                 OmniPaxosMessage::BLE(_) => {
-                    ble_msg_buf.push((0, addr, msg));
+                    // heartbeats are not retransmitted, they are dropped while the peer backs off or the last ones are on their way
+                    let link = rsm.links.entry(receiver_id).or_default();
+                    if !link.heartbeating && !link.backing_off(now) {
+                        ble_msgs.entry(receiver_id).or_insert_with(|| (addr, vec![])).1.push((0, config_id, msg));
+                    }
                 },
             }
-        });
+        }
+        for pid in ble_msgs.keys() {
+            rsm.links.entry(*pid).or_default().heartbeating = true;
+        }
+        // new messages are persisted before they are sent, so a restart can neither lose nor renumber them,
+        // the queues are persisted again until that worked
+        (storage::persistent() && (queued || !PL_OUTGOING.is_durable())).then(|| take_outgoing(rsm))
+    };
+
+    // a lost heartbeat is replaced by the next
+    for (pid, (addr, msgs)) in ble_msgs {
+        tokio::spawn(send_heartbeats(pid, addr, msgs));
+    }
+    let written = match outgoing {
+        Some(Ok(outgoing)) => PL_OUTGOING.write(outgoing).await,
+        Some(Err(err)) => Err(err),
+        None => Ok(()),
+    };
+    if let Err(err) = written {
+        println!("failed to persist outgoing messages: {}", err);
+        return
     }
 
-    // actually send the messages, first new BLE messages, then SequencePaxos Messages via our FIFO PL
-    let mut num_successfully_sent = 0;
-    let out_buf = RSM::instance().lock().unwrap().outgoing_buffer.clone();
-    for (sequence_id, addr, msg) in ble_msg_buf.clone().into_iter().chain(out_buf.into_iter()) {
-        let url = format!("http://{}/omnipaxos", addr);
-        match reqwest::Client::new().post(url).json(&(sequence_id, msg)).send().await {
-            Ok(_) => { num_successfully_sent += 1 },
-            Err(_) => break,
+    {
+        let unlocked = RSM::instance();
+        let mut guard = unlocked.lock().unwrap();
+        let rsm = &mut *guard;
+        // every peer gets the oldest messages it has not acked yet, unless a send to it is still running or backing off
+        let now = time::Instant::now();
+        for (pid, link) in rsm.links.iter_mut() {
+            if link.sending || link.queue.is_empty() || link.backing_off(now) {
+                continue
+            }
+            if let Some(addr) = rsm.addrs.get(pid) {
+                link.sending = true;
+                batches.push((*pid, addr.clone(), link.queue.iter().take(*PL_WINDOW).cloned().collect()));
+            }
         }
     }
-    // remove sent SequencePaxos messages from our PL buffer
-    if num_successfully_sent > ble_msg_buf.len() {
-        let num_to_remove = num_successfully_sent - ble_msg_buf.len();
+
+    // peers are sent to independently, so an unreachable one does not hold up the others
+    for (pid, addr, batch) in batches {
+        tokio::spawn(send_batch(pid, addr, batch));
+    }
+}
+
+/// Sends a batch of messages to a peer, and drops them from its queue once acked.
+/// Unreachable peers are retried with exponential backoff.
+#[cfg(feature = "pl")]
+async fn send_batch(pid: NodeId, addr: String, batch: Batch) {
+    let ack = post_msgs(&addr, &batch).await;
+    let outgoing = {
         let unlocked = RSM::instance();
         let mut rsm = unlocked.lock().unwrap();
-        for _ in 0..num_to_remove {
-            rsm.outgoing_buffer.remove(0);
+        // the peer left the configuration in the meantime
+        let link = match rsm.links.get_mut(&pid) {
+            Some(link) => link,
+            None => return,
+        };
+        link.sending = false;
+        link.reached(ack.is_some());
+        match ack {
+            Some(ack) => {
+                let queued = link.queue.len();
+                while link.queue.front().is_some_and(|(sequence_id, _, _)| *sequence_id <= ack) {
+                    link.queue.pop_front();
+                }
+                // acked messages only have to be dropped from disk eventually, a restart before that sends them again
+                (storage::persistent() && link.queue.len() < queued).then(|| take_outgoing(&rsm))
+            },
+            None => None,
         }
+    };
+    if let Some(outgoing) = outgoing {
+        let written = match outgoing {
+            Ok(outgoing) => PL_OUTGOING.write(outgoing).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = written {
+            println!("failed to persist outgoing messages: {}", err);
+        }
+    }
+}
+
+/// Sends heartbeats to a peer, which counts as unreachable like for any other send if they do not arrive
+#[cfg(feature = "pl")]
+async fn send_heartbeats(pid: NodeId, addr: String, msgs: Batch) {
+    let ack = post_msgs(&addr, &msgs).await;
+    let unlocked = RSM::instance();
+    let mut rsm = unlocked.lock().unwrap();
+    if let Some(link) = rsm.links.get_mut(&pid) {
+        link.heartbeating = false;
+        link.reached(ack.is_some());
     }
 }
 
+/// Posts messages with their sequence id and configuration to a peer, and returns its cumulative ack.
+/// A peer that does not answer within an election timeout counts as unreachable.
+#[cfg(feature = "pl")]
+async fn post_msgs(addr: &str, msgs: &[(u64, u32, OmniPaxosMessage)]) -> Option<u64> {
+    let url = format!("http://{}/omnipaxos", addr);
+    let resp = reqwest::Client::new().post(url)
+        .timeout(time::Duration::from_millis(*ELECTION_TIMEOUT))
+        .json(msgs)
+        .send().await.ok()?;
+    resp.json::<u64>().await.ok()
+}
+
 /// Our main OmniPaxos event loop
 pub async fn run() {
     let mut outgoing_interval = time::interval(time::Duration::from_millis(*OUTGOING_INTERVAL));
@@ -348,39 +1057,126 @@ pub async fn run() {
     loop {
         tokio::select! {
             biased;
-            _ = election_interval.tick() => { RSM::instance().lock().unwrap().omnipaxos.election_timeout(); },
+            _ = election_interval.tick() => {
+                let unlocked = RSM::instance();
+                let mut rsm = unlocked.lock().unwrap();
+                rsm.release_deferred();
+                // a node that granted another leader a lease does not try to take over before it ran out
+                if !rsm.granted_to_other(*PID) {
+                    rsm.omnipaxos.election_timeout();
+                }
+                if let Some(ref mut retired) = rsm.retired {
+                    retired.omnipaxos.election_timeout();
+                }
+                rsm.propose_carry_over();
+            },
             _ = outgoing_interval.tick() => { send_outgoing_msgs().await; },
             else => {},
         }
     }
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct MsgParams {
+    /// the configuration a message belongs to
+    config: u32,
+}
+
 /// Receives an omnipaxos message and delivers it
 #[cfg(not(feature = "pl"))]
-pub async fn handle_msg_http(Json(msg): Json<OmniPaxosMessage>) {
+pub async fn handle_msg_http(Query(params): Query<MsgParams>, Json(msg): Json<OmniPaxosMessage>) {
     let unlocked = RSM::instance();
     let mut rsm = unlocked.lock().unwrap();
     if let Message::SequencePaxos(ref x) = msg {
         println!("{:?}", x);
     }
-    rsm.omnipaxos.handle_incoming(msg.clone());
+    rsm.deliver(params.config, msg);
 }
 
-/// Receives an omnipaxos message and delivers it exactly once
+/// Receives a batch of omnipaxos messages from one sender and delivers each of them exactly once.
+/// Acks with the sequence id up to which all messages of the sender were delivered.
 #[cfg(feature = "pl")]
-pub async fn handle_msg_http(Json((sequence_id, msg)): Json<(u64, OmniPaxosMessage)>) {
+pub async fn handle_msg_http(Json(msgs): Json<Batch>) -> Result<Json<u64>> {
+    let (delivered, ack) = {
+        let unlocked = RSM::instance();
+        let mut rsm = unlocked.lock().unwrap();
+        let sender = match msgs.first() {
+            Some((_, _, msg)) => msg.get_sender(),
+            None => return Ok(Json(0)),
+        };
+        let mut delivered_any = false;
+        for (sequence_id, config_id, msg) in msgs {
+            if let Message::SequencePaxos(ref x) = msg {
+                println!("{}: {:?}", sequence_id, x);
+            }
+            if let OmniPaxosMessage::SequencePaxos(_) = msg {
+                if !rsm.delivered_msgs.entry(sender).or_default().accept(sequence_id) {
+                    continue
+                }
+                delivered_any = true;
+            }
+            rsm.deliver(config_id, msg);
+        }
+        let delivered = storage::persistent() && (delivered_any || !PL_DELIVERED.is_durable());
+        (delivered.then(|| PL_DELIVERED.take(&rsm.delivered_msgs)), rsm.delivered_msgs.get(&sender).map_or(0, |delivered| delivered.high_water_mark))
+    };
+    // persisted before the sender gets its ack and forgets the messages, without an ack it sends them again.
+    // a crash between delivering and persisting may deliver them again after the restart
+    if let Some(delivered) = delivered {
+        PL_DELIVERED.write(delivered?).await?;
+    }
+    Ok(Json(ack))
+}
+
+/// Tells a leader which ballot this node has promised in a configuration, the leader needs a quorum
+/// that promised its own ballot to serve reads. Grants the promised leader a lease for an election timeout.
+pub async fn handle_heartbeat(Query(params): Query<MsgParams>) -> Json<Option<Ballot>> {
     let unlocked = RSM::instance();
     let mut rsm = unlocked.lock().unwrap();
-    if let Message::SequencePaxos(ref x) = msg {
-        println!("{}: {:?}", sequence_id, x);
+    if rsm.config_id != params.config {
+        return Json(None)
     }
-    if let OmniPaxosMessage::SequencePaxos(_) = msg {
-        let delivered_msgs = rsm.delivered_msgs.get_mut(&msg.get_sender()).unwrap();
-        if delivered_msgs.contains(&sequence_id) {
-            return
-        } else {
-            delivered_msgs.push(sequence_id);
-        }
+    let promise = *rsm.promise.lock().unwrap();
+    if promise.pid != *PID {
+        let until = time::Instant::now() + time::Duration::from_millis(*ELECTION_TIMEOUT);
+        rsm.lease_grant = (Some(promise.pid), until);
+    }
+    Json(Some(promise))
+}
+
+/// Serves the read index to followers, only while this node leads
+pub async fn handle_read_index() -> Result<Json<u64>> {
+    Ok(Json(leader_read_index(false).await?))
+}
+
+/// Serves this node's decided index to followers checking how far they lag behind
+pub async fn handle_decided_index() -> Json<u64> {
+    Json(decided_index())
+}
+
+#[cfg(all(test, feature = "pl"))]
+mod tests {
+    use super::*;
+
+    /// Retransmissions are dropped, and reordered messages only count once the gap before them is filled
+    #[test]
+    fn delivered_messages_are_tracked_by_high_water_mark() {
+        let mut delivered = Delivered::default();
+        assert!(delivered.accept(1));
+        assert!(delivered.accept(2));
+        assert!(!delivered.accept(2));
+        assert!(delivered.accept(4));
+        assert_eq!(delivered.high_water_mark, 2);
+        assert!(!delivered.accept(4));
+        assert!(!delivered.accept(3 + REORDER_WINDOW));
+        assert!(delivered.accept(3));
+        assert_eq!(delivered.high_water_mark, 4);
+        assert!(delivered.ahead.is_empty());
+        assert!(!delivered.accept(1));
+
+        // a receiver that lost its state continues from the first message it gets
+        let mut restarted = Delivered::default();
+        assert!(restarted.accept(7));
+        assert_eq!(restarted.high_water_mark, 7);
     }
-    rsm.omnipaxos.handle_incoming(msg.clone());
 }
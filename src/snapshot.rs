@@ -1,64 +1,562 @@
+use crate::apply::{self, Keyspace, Versioned};
 use crate::rsm::RSMCommand;
+use crate::store::StoreState;
 use crate::types::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use omnipaxos_core::storage::Snapshot;
 use serde::{Serialize, Deserialize};
 
+/// Resolved state of a key. Revisions are kept as offsets into the snapshot, because
+/// the log position of a snapshot is only known when it is restored or merged onto the start of the log.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct KeyState {
+    pub value: Value,
+    pub create_offset: u64,
+    pub mod_offset: u64,
+    pub version: u64,
+    #[serde(default)]
+    pub lease: Option<LeaseId>,
+    /// the revisions, once the snapshot is merged onto the start of the log
+    #[serde(default)]
+    pub create_revision: Option<Revision>,
+    #[serde(default)]
+    pub mod_revision: Option<Revision>,
+}
+
+impl Versioned for KeyState {
+    fn value(&self) -> &Value {
+        &self.value
+    }
+
+    fn version(&self) -> u64 {
+        self.version
+    }
+
+    fn created(&self) -> u64 {
+        self.create_offset
+    }
+
+    fn mod_revision(&self) -> Option<Revision> {
+        self.mod_revision
+    }
+}
+
+/// What the commands of a key segment start from
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum KeyBase {
+    /// whatever state the key had before
+    Older,
+    /// the key does not exist, this tombstone also deletes it when merged onto an older snapshot
+    Absent,
+    State(KeyState),
+}
+
+/// A resolved state of a key, and the commands on top of it that could not be resolved
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KeySegment {
+    pub base: KeyBase,
+    pub cmds: Vec<(u64, RSMCommand)>,
+}
+
+impl KeySegment {
+    fn new(base: KeyBase) -> Self {
+        Self { base, cmds: vec![] }
+    }
+
+    /// The state of the key after this segment, if it does not depend on anything before the snapshot
+    fn known(&self) -> Option<Option<&KeyState>> {
+        if !self.cmds.is_empty() {
+            return None
+        }
+        match self.base {
+            KeyBase::Older => None,
+            KeyBase::Absent => Some(None),
+            KeyBase::State(ref state) => Some(Some(state)),
+        }
+    }
+}
+
+/// The effect of a snapshot on a key. Usually a single segment, but a transaction has to be replayed
+/// against the state its keys had at the time, so a segment with one is kept when the key is overwritten.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KeyEntry {
+    pub segments: Vec<KeySegment>,
+}
+
+/// One step of restoring a snapshot
+#[derive(Debug)]
+pub enum SnapshotOp {
+    /// replaces a key with its resolved state, `None` deletes it
+    Reset(Key, Option<KeyState>),
+    Apply(RSMCommand),
+    /// deletes all keys
+    Clear,
+    /// the last sequence number of a client session, whose command may have been dropped from the snapshot
+    Session(String, u64),
+}
+
+/// What is known while a snapshot that starts the log is rebuilt
+#[derive(Clone, Debug, Default)]
+struct Known {
+    /// the revision right before the log, the one of the store carried over by the migration
+    revision: Revision,
+    /// live leases, and whether they were carried over by the migration
+    leases: HashMap<LeaseId, bool>,
+    /// sessions carried over by the migration that did not expire yet
+    sessions: HashSet<String>,
+}
+
+/// Snapshot of a range of the log. Keys are resolved to their state at the end of that range,
+/// as far as it does not depend on anything before it. Other commands are kept with their offset
+/// into the range, so replicas restoring from a snapshot agree on key revisions. A merged snapshot
+/// starts the log, so it depends on nothing before it and every command in it is resolved.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct OPSnapshot {
-    pub snapshotted: HashMap<Key, Vec<RSMCommand>>,
-    pub clear: bool,
+    pub keys: HashMap<Key, KeyEntry>,
+    /// grants and revocations of leases, revoked leases are kept because the key commands in the snapshot
+    /// may still refer to them. Once merged, only grants of live leases and revocations of carried over ones.
+    pub leases: Vec<(u64, RSMCommand)>,
+    /// the first entry of a configuration, which carries over the store from the previous one
+    pub migrate: Option<(u64, RSMCommand)>,
+    /// offset and sequence number of the last command of every live client session,
+    /// the commands themselves may have been dropped by a later delete or clear
+    pub sessions: HashMap<String, (u64, u64)>,
+    /// expirations of client sessions, once merged only of carried over ones
+    pub expired_sessions: Vec<(u64, RSMCommand)>,
+    /// clears of client sessions that may retry a clear from before this snapshot
+    pub clears: Vec<(u64, RSMCommand)>,
+    /// offset of the last clear, keys that are not in the snapshot do not exist after it
+    pub cleared: Option<u64>,
+    /// number of log entries covered by this snapshot
+    pub len: u64,
+    /// only set while the snapshot is rebuilt
+    #[serde(skip)]
+    known: Option<Known>,
 }
 
-impl Snapshot<RSMCommand> for OPSnapshot {
-    fn create(entries: &[RSMCommand]) -> Self {
-        let mut snapshotted = HashMap::new();
-        let mut clear = false;
-        for cmd in entries {
-            match cmd {
-                RSMCommand::LinearizableRead(_) => (),
-                RSMCommand::Put((_, kv)) => { snapshotted.insert(kv.key.clone(), vec![cmd.clone()]); },
-                RSMCommand::Delete((_, key)) => { snapshotted.insert(key.clone(), vec![cmd.clone()]); },
-                RSMCommand::CAS((_, kv, _)) => {
-                    if let Some(x) = snapshotted.get_mut(&kv.key) {
-                        x.push(cmd.clone());
-                    } else {
-                        snapshotted.insert(kv.key.clone(), vec![cmd.clone()]);
+/// Whether commands include a transaction, which may depend on other keys
+fn has_txn(cmds: &[(u64, RSMCommand)]) -> bool {
+    cmds.iter().any(|(_, cmd)| matches!(cmd.inner(), RSMCommand::Txn(_)))
+}
+
+impl KeyEntry {
+    fn new(base: KeyBase) -> Self {
+        Self { segments: vec![KeySegment::new(base)] }
+    }
+
+    /// Overwrites the key with a resolved state
+    fn reset(&mut self, base: KeyBase) {
+        let last = self.segments.last_mut().expect("key entries have a segment");
+        if has_txn(&last.cmds) {
+            self.segments.push(KeySegment::new(base));
+        } else {
+            *last = KeySegment::new(base);
+        }
+    }
+
+    /// The state of the key, if it does not depend on anything before the snapshot
+    fn known(&self) -> Option<Option<&KeyState>> {
+        self.segments.last().expect("key entries have a segment").known()
+    }
+
+    /// Keeps a command on this key that could not be resolved
+    fn push(&mut self, offset: u64, cmd: RSMCommand) {
+        self.segments.last_mut().expect("key entries have a segment").cmds.push((offset, cmd));
+    }
+
+    /// Whether the key is just deleted
+    fn is_absent(&self) -> bool {
+        matches!(self.segments[..], [KeySegment{ base: KeyBase::Absent, ref cmds }] if cmds.is_empty())
+    }
+}
+
+/// Commands are resolved against the keys of the snapshot, leases are only known while it is rebuilt
+impl Keyspace for OPSnapshot {
+    type State = KeyState;
+
+    fn state(&self, key: &Key) -> Option<Option<&KeyState>> {
+        self.keys.get(key)?.known()
+    }
+
+    fn lease_exists(&self, id: LeaseId) -> Option<bool> {
+        self.known.as_ref().map(|known| known.leases.contains_key(&id))
+    }
+
+    fn write(&mut self, key: &Key, value: Option<(&Value, Option<LeaseId>)>, at: u64) {
+        let revision = self.known.as_ref().map(|known| known.revision + at + 1);
+        let entry = self.keys.get_mut(key).expect("commands are only applied to keys of the snapshot");
+        let base = match value {
+            Some((value, lease)) => {
+                let prev = entry.known().expect("only known keys are written");
+                let (create_offset, version) = apply::written(prev, at);
+                let create_revision = match prev {
+                    Some(prev) => prev.create_revision,
+                    None => revision,
+                };
+                KeyBase::State(KeyState{ value: value.clone(), create_offset, mod_offset: at, version, lease, create_revision, mod_revision: revision })
+            },
+            None => KeyBase::Absent,
+        };
+        entry.reset(base);
+    }
+}
+
+impl OPSnapshot {
+    fn empty() -> Self {
+        Self {
+            keys: HashMap::new(),
+            leases: vec![],
+            migrate: None,
+            sessions: HashMap::new(),
+            expired_sessions: vec![],
+            clears: vec![],
+            cleared: None,
+            len: 0,
+            known: None,
+        }
+    }
+
+    /// The store carried over by the migration, while the snapshot is rebuilt
+    fn carried(&self) -> Option<&StoreState> {
+        match (&self.known, &self.migrate) {
+            (Some(_), Some((_, RSMCommand::Migrate((_, state))))) => Some(state),
+            _ => None,
+        }
+    }
+
+    /// What a key that is not in the snapshot starts from. It is absent after a clear, and while the
+    /// snapshot is rebuilt, the keys carried over by the migration are the only ones before it.
+    fn untouched(&self, key: &Key) -> KeyBase {
+        if self.cleared.is_some() {
+            return KeyBase::Absent
+        }
+        if self.known.is_none() {
+            return KeyBase::Older
+        }
+        match self.carried().and_then(|state| state.get(key)) {
+            Some(kv) => KeyBase::State(KeyState{
+                value: kv.value,
+                create_offset: 0,
+                mod_offset: 0,
+                version: kv.version,
+                lease: kv.lease,
+                create_revision: Some(kv.create_revision),
+                mod_revision: Some(kv.mod_revision),
+            }),
+            None => KeyBase::Absent,
+        }
+    }
+
+    fn entry(&mut self, key: Key) -> &mut KeyEntry {
+        if !self.keys.contains_key(&key) {
+            let base = self.untouched(&key);
+            self.keys.insert(key.clone(), KeyEntry::new(base));
+        }
+        self.keys.get_mut(&key).expect("the entry was just added")
+    }
+
+    /// Deletes all keys at `offset`, leases survive a clear, only their keys are gone
+    fn clear_keys(&mut self, offset: u64) {
+        for entry in self.keys.values_mut() {
+            entry.reset(KeyBase::Absent);
+        }
+        // untouched keys are absent after a clear anyway
+        self.keys.retain(|_, entry| !entry.is_absent());
+        self.cleared = Some(offset);
+    }
+
+    /// Applies a command on `keys` if its outcome is known, otherwise keeps it with every key it touches. A session
+    /// command is only applied once it is `decided` that it does not retry a command from before the snapshot.
+    fn apply_keys(&mut self, keys: Vec<Key>, offset: u64, cmd: &RSMCommand, decided: bool) {
+        for key in keys.iter() {
+            self.entry(key.clone());
+        }
+        // a command that failed without any effect is dropped as well
+        if decided && apply::apply(self, cmd.inner(), offset).is_some() {
+            return
+        }
+        for key in keys {
+            self.entry(key).push(offset, cmd.clone());
+        }
+    }
+
+    /// Revokes a live lease at `offset` while the snapshot is rebuilt, which deletes its keys
+    fn revoke(&mut self, id: LeaseId, carried: bool, offset: u64, cmd: &RSMCommand) {
+        // keys carried over with the lease only have an entry once they are touched
+        let carried_keys: Vec<Key> = self.carried()
+            .and_then(|state| state.leases().get(&id))
+            .map_or(vec![], |(_, keys)| keys.iter().cloned().collect());
+        for key in carried_keys {
+            self.entry(key);
+        }
+        for entry in self.keys.values_mut() {
+            if matches!(entry.known(), Some(Some(state)) if state.lease == Some(id)) {
+                entry.reset(KeyBase::Absent);
+            }
+        }
+        let known = self.known.as_mut().expect("leases are only known while the snapshot is rebuilt");
+        known.leases.remove(&id);
+        if carried {
+            // restoring loads the lease with the migration, so it still has to be revoked
+            self.leases.push((offset, cmd.clone()));
+        } else {
+            let granted = id - known.revision - 1;
+            self.leases.retain(|(offset, _)| *offset != granted);
+        }
+    }
+
+    /// Adds the command at `offset`, commands are added in log order
+    fn add(&mut self, offset: u64, cmd: &RSMCommand) {
+        let mut decided = true;
+        if let RSMCommand::Session((session, _)) = cmd {
+            // a retry of a command of this snapshot is never applied
+            if self.sessions.get(&session.id).is_some_and(|(_, seq)| *seq >= session.seq) {
+                return
+            }
+            self.sessions.insert(session.id.clone(), (offset, session.seq));
+            // unless the snapshot is rebuilt, it may retry a command from before this snapshot
+            decided = self.known.is_some();
+        }
+        // once decided, only the sequence number of the session matters
+        let cmd = if decided { cmd.inner() } else { cmd };
+        // session commands are filed by the command they wrap
+        match cmd.inner() {
+            RSMCommand::LinearizableRead(_) => (),
+            // keep alives only refresh local lease deadlines
+            RSMCommand::LeaseKeepAlive(_) => (),
+            RSMCommand::Put((_, kv, _)) | RSMCommand::CAS((_, kv, _)) => self.apply_keys(vec![kv.key.clone()], offset, cmd, decided),
+            RSMCommand::Delete((_, key)) => self.apply_keys(vec![key.clone()], offset, cmd, decided),
+            // a transaction is kept with every key it touches, restoring applies it only once
+            RSMCommand::Txn((_, txn)) => self.apply_keys(txn.keys(), offset, cmd, decided),
+            RSMCommand::Clear(_) => if decided {
+                self.clear_keys(offset);
+                self.clears.clear();
+            } else {
+                // keeps the keys from being resolved across it, until it is known whether it applies
+                for entry in self.keys.values_mut() {
+                    entry.push(offset, cmd.clone());
+                }
+                self.clears.push((offset, cmd.clone()));
+            },
+            RSMCommand::LeaseGrant(_) => {
+                if let Some(ref mut known) = self.known {
+                    known.leases.insert(known.revision + offset + 1, false);
+                }
+                self.leases.push((offset, cmd.clone()));
+            },
+            RSMCommand::LeaseRevoke((_, id)) => match self.known.as_ref().map(|known| known.leases.get(id).copied()) {
+                Some(Some(carried)) => self.revoke(*id, carried, offset, cmd),
+                // revoking a lease that does not exist changes nothing
+                Some(None) => (),
+                None => self.leases.push((offset, cmd.clone())),
+            },
+            RSMCommand::Migrate((_, state)) => match self.known {
+                // only the first entry of the log carries over the store, later ones were proposed again after a leader change
+                Some(ref mut known) => if offset == 0 {
+                    known.revision = state.revision();
+                    known.leases = state.leases().keys().map(|id| (*id, true)).collect();
+                    known.sessions = state.sessions().keys().cloned().collect();
+                    for (id, seq) in state.sessions() {
+                        self.sessions.insert(id.clone(), (offset, *seq));
                     }
+                    self.migrate = Some((offset, cmd.clone()));
                 },
-                RSMCommand::Clear(_) => {
-                    snapshotted.clear(); clear = true;
+                None => if self.migrate.is_none() {
+                    self.migrate = Some((offset, cmd.clone()));
                 },
+            },
+            RSMCommand::ExpireSessions((cmd_id, ids)) => {
+                for id in ids {
+                    self.sessions.remove(id);
+                }
+                match self.known {
+                    // restoring only has to expire the sessions that the migration loads
+                    Some(ref mut known) => {
+                        let carried: Vec<String> = ids.iter().filter(|id| known.sessions.remove(*id)).cloned().collect();
+                        if !carried.is_empty() {
+                            self.expired_sessions.push((offset, RSMCommand::ExpireSessions((*cmd_id, carried))));
+                        }
+                    },
+                    None => self.expired_sessions.push((offset, cmd.clone())),
+                }
+            },
+            RSMCommand::Session(_) => unreachable!("inner commands have no session"),
+        }
+    }
+
+    /// Remembers the last sequence number of a session, unless a later one is known already
+    fn mark(&mut self, id: String, offset: u64, seq: u64) {
+        if self.sessions.get(&id).is_none_or(|(_, known)| *known < seq) {
+            self.sessions.insert(id, (offset, seq));
+        }
+    }
+
+    /// Replaces a key with a resolved state while the snapshot is rebuilt, its revisions are known then
+    fn reset(&mut self, key: Key, state: Option<KeyState>) {
+        let start = self.known.as_ref().expect("only rebuilt snapshots are reset").revision + 1;
+        let base = match state {
+            Some(state) => KeyBase::State(KeyState{
+                create_revision: state.create_revision.or(Some(start + state.create_offset)),
+                mod_revision: state.mod_revision.or(Some(start + state.mod_offset)),
+                ..state
+            }),
+            None => KeyBase::Absent,
+        };
+        self.entry(key).reset(base);
+    }
+
+    /// Rebuilds a snapshot that starts the log from the steps that restore it. Every key, lease and session
+    /// is known then, so every command is resolved, and only what restoring still needs is kept.
+    fn rebuild(steps: impl Iterator<Item = (u64, SnapshotOp)>, len: u64) -> Self {
+        let mut snapshot = Self::empty();
+        snapshot.known = Some(Known::default());
+        for (offset, op) in steps {
+            match op {
+                SnapshotOp::Reset(key, state) => snapshot.reset(key, state),
+                SnapshotOp::Apply(cmd) => snapshot.add(offset, &cmd),
+                SnapshotOp::Clear => snapshot.clear_keys(offset),
+                SnapshotOp::Session(id, seq) => snapshot.mark(id, offset, seq),
             }
         }
-        Self { snapshotted, clear }
+        snapshot.len = len;
+        // keys that are still like the migration carried them over, or absent anyway, need no entry
+        let keys = std::mem::take(&mut snapshot.keys);
+        snapshot.keys = keys.into_iter()
+            .filter(|(key, entry)| !matches!(&entry.segments[..], [KeySegment{ base, cmds }] if cmds.is_empty() && *base == snapshot.untouched(key)))
+            .collect();
+        snapshot.known = None;
+        snapshot
     }
 
-    fn merge(&mut self, delta: Self) {
-        if delta.clear {
-            self.clear = true;
-            self.snapshotted.clear();
-        }
-        for (k, v) in delta.snapshotted {
-            for cmd in v {
-                match cmd {
-                    RSMCommand::Clear(_) => (),
-                    RSMCommand::LinearizableRead(_) => (),
-                    RSMCommand::Put(_) => { self.snapshotted.insert(k.clone(), vec![cmd.clone()]); },
-                    RSMCommand::Delete(_) => { self.snapshotted.insert(k.clone(), vec![cmd.clone()]); },
-                    RSMCommand::CAS(_) => {
-                        if let Some(x) = self.snapshotted.get_mut(&k) {
-                            x.push(cmd.clone());
-                        } else {
-                            self.snapshotted.insert(k.clone(), vec![cmd.clone()]);
-                        }
-                    },
+    /// All steps to restore this snapshot, in log order. A key is reset to a resolved state right before
+    /// the first command on top of it, so commands that read the key see the state it had at the time.
+    pub fn replay(self) -> Vec<(u64, SnapshotOp)> {
+        // at the same offset resets come first, then the commands, a clear and the sessions
+        let mut ops: Vec<(u64, u8, SnapshotOp)> = vec![];
+        for (key, entry) in self.keys {
+            for segment in entry.segments {
+                let at = segment.cmds.first().map_or(self.len, |(offset, _)| *offset);
+                match segment.base {
+                    KeyBase::Older => (),
+                    KeyBase::Absent => ops.push((at, 0, SnapshotOp::Reset(key.clone(), None))),
+                    KeyBase::State(state) => ops.push((at, 0, SnapshotOp::Reset(key.clone(), Some(state)))),
                 }
+                ops.extend(segment.cmds.into_iter().map(|(offset, cmd)| (offset, 1, SnapshotOp::Apply(cmd))));
             }
         }
+        let cmds = self.leases.into_iter().chain(self.migrate).chain(self.expired_sessions).chain(self.clears);
+        ops.extend(cmds.map(|(offset, cmd)| (offset, 1, SnapshotOp::Apply(cmd))));
+        // the clear itself is not kept, but keys carried over by a migration still have to go
+        if let Some(offset) = self.cleared {
+            ops.push((offset, 2, SnapshotOp::Clear));
+        }
+        ops.extend(self.sessions.into_iter().map(|(id, (offset, seq))| (offset, 3, SnapshotOp::Session(id, seq))));
+        ops.sort_by_key(|(offset, order, _)| (*offset, *order));
+        // transactions are kept with every key they touch
+        ops.dedup_by(|a, b| a.1 == 1 && b.1 == 1 && a.0 == b.0);
+        ops.into_iter().map(|(offset, _, op)| (offset, op)).collect()
+    }
+}
+
+impl Snapshot<RSMCommand> for OPSnapshot {
+    fn create(entries: &[RSMCommand]) -> Self {
+        let mut snapshot = Self::empty();
+        for (i, cmd) in entries.iter().enumerate() {
+            snapshot.add(i as u64, cmd);
+        }
+        snapshot.len = entries.len() as u64;
+        snapshot
+    }
+
+    /// Appends a snapshot of the entries right after this one. This snapshot has to start at the beginning of the log,
+    /// so both are replayed into a rebuilt snapshot, which resolves everything that depended on state before `delta`.
+    fn merge(&mut self, delta: Self) {
+        let len = self.len;
+        let end = len + delta.len;
+        let delta = delta.replay().into_iter().map(|(offset, op)| (len + offset, match op {
+            SnapshotOp::Reset(key, Some(state)) => SnapshotOp::Reset(key, Some(KeyState{
+                create_offset: len + state.create_offset,
+                mod_offset: len + state.mod_offset,
+                ..state
+            })),
+            op => op,
+        }));
+        let steps = std::mem::replace(self, Self::empty()).replay().into_iter().chain(delta);
+        *self = Self::rebuild(steps, end);
     }
 
     fn use_snapshots() -> bool {
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kv(key: &str, value: &str) -> KeyValue {
+        KeyValue{ key: key.to_owned(), value: value.to_owned() }
+    }
+
+    /// Overwritten values are not kept once the state of a key is known
+    #[test]
+    fn snapshots_keep_only_the_resolved_state() {
+        let put = |i: u64| RSMCommand::Put(((0, i), kv("k", &i.to_string()), None));
+        let mut snapshot = OPSnapshot::create(&(0..10).map(put).collect::<Vec<_>>());
+        snapshot.merge(OPSnapshot::create(&(10..20).map(put).collect::<Vec<_>>()));
+        let ops = snapshot.replay();
+        assert_eq!(ops.len(), 1);
+        match &ops[0] {
+            (20, SnapshotOp::Reset(key, Some(state))) => {
+                assert_eq!(key, "k");
+                assert_eq!(state, &KeyState{
+                    value: "19".to_owned(),
+                    create_offset: 0,
+                    mod_offset: 19,
+                    version: 20,
+                    lease: None,
+                    create_revision: Some(1),
+                    mod_revision: Some(20),
+                });
+            },
+            op => panic!("unexpected {:?}", op),
+        }
+    }
+
+    /// Commands on leases, sessions or other keys are kept while they depend on state before the snapshot,
+    /// but once merged onto the start of the log they are resolved, and nothing superseded is left
+    #[test]
+    fn merged_snapshots_drop_superseded_commands() {
+        let txn = TxnRequest{
+            compare: vec![Compare{ key: "a".to_owned(), op: CompareOp::Equal, target: CompareTarget::Value("1".to_owned()) }],
+            success: vec![TxnOp::Put(PutRequest{ key: "b".to_owned(), value: "2".to_owned(), lease: None })],
+            failure: vec![],
+        };
+        let log = [
+            // grants lease 1
+            RSMCommand::LeaseGrant(((0, 0), 60)),
+            RSMCommand::Put(((0, 1), kv("a", "1"), Some(1))),
+            // creates b at revision 3
+            RSMCommand::Txn(((0, 2), txn)),
+            RSMCommand::CAS(((0, 3), kv("b", "3"), CASCondition::ModRevision(3))),
+            RSMCommand::LeaseRevoke(((0, 4), 1)),
+            RSMCommand::ExpireSessions(((0, 5), vec!["s".to_owned()])),
+        ];
+        let delta = OPSnapshot::create(&log[1..]);
+        assert_eq!(delta.leases.len(), 1);
+        assert_eq!(delta.expired_sessions.len(), 1);
+        assert!(delta.keys.values().all(|entry| entry.known().is_none()));
+
+        let mut snapshot = OPSnapshot::create(&log[..1]);
+        snapshot.merge(delta);
+        let ops = snapshot.replay();
+        assert_eq!(ops.len(), 1);
+        match &ops[0] {
+            (6, SnapshotOp::Reset(key, Some(state))) => {
+                assert_eq!(key, "b");
+                assert_eq!((state.value.as_str(), state.create_revision, state.mod_revision), ("3", Some(3), Some(4)));
+            },
+            op => panic!("unexpected {:?}", op),
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use crate::rsm::RSMCommand;
+use crate::snapshot::OPSnapshot;
+use crate::error::Result;
+use omnipaxos_core::{ballot_leader_election::Ballot, storage::{Storage, StopSignEntry}};
+use omnipaxos_storage::{memory_storage::MemoryStorage, persistent_storage::{PersistentStorage, PersistentStorageConfig}};
+use std::{env, io::Write, path::PathBuf, sync::{Arc, Mutex}};
+
+/// Where omnipaxos keeps its log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageKind {
+    Memory,
+    Sled,
+}
+
+lazy_static! {
+    /// everything a node persists goes into this directory
+    pub static ref DATA_DIR: PathBuf = if let Ok(var) = env::var("DATA_DIR") {
+        PathBuf::from(var)
+    } else {
+        PathBuf::from("/data")
+    };
+
+    /// "memory" or "sled", builds with the crash_recovery feature use sled by default
+    pub static ref STORAGE: StorageKind = match env::var("STORAGE").as_deref() {
+        Ok("memory") => StorageKind::Memory,
+        Ok("sled") => StorageKind::Sled,
+        Ok(_) => panic!("STORAGE must be memory or sled"),
+        Err(_) if cfg!(feature = "crash_recovery") => StorageKind::Sled,
+        Err(_) => StorageKind::Memory,
+    };
+}
+
+/// Whether this node keeps its state across restarts
+pub fn persistent() -> bool {
+    *STORAGE == StorageKind::Sled
+}
+
+/// Path of a file or directory in the data directory
+pub fn data_path(name: &str) -> PathBuf {
+    DATA_DIR.join(name)
+}
+
+/// Name of a file of a configuration, the first configuration keeps the original name, so existing data stays readable
+pub fn config_file(name: &str, configuration_id: u32) -> String {
+    if configuration_id == 1 {
+        name.to_owned()
+    } else {
+        format!("{}_{}", name, configuration_id)
+    }
+}
+
+/// Replaces a file in the data directory through a temporary file, so a crash leaves either the old or the new content
+pub fn write_atomically(name: &str, bytes: &[u8]) -> Result<()> {
+    let tmp = data_path(&format!("{}.tmp", name));
+    let mut file = std::fs::File::create(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, data_path(name))?;
+    // the rename itself is only durable once the directory is synced
+    std::fs::File::open(&*DATA_DIR)?.sync_all()?;
+    Ok(())
+}
+
+/// The storage backend of omnipaxos, as chosen by `STORAGE`
+enum Backend {
+    Memory(MemoryStorage<RSMCommand, OPSnapshot>),
+    Sled(PersistentStorage<RSMCommand, OPSnapshot>),
+}
+
+/// The storage of omnipaxos, which also shares the promised ballot with the rest of the node
+pub struct OmniPaxosStorage {
+    backend: Backend,
+    promise: Arc<Mutex<Ballot>>,
+}
+
+impl OmniPaxosStorage {
+    /// Opens the storage of a configuration, every configuration has its own log
+    pub fn open(configuration_id: u32) -> Self {
+        let backend = match *STORAGE {
+            StorageKind::Memory => Backend::Memory(MemoryStorage::default()),
+            StorageKind::Sled => {
+                std::fs::create_dir_all(&*DATA_DIR).expect("failed to create DATA_DIR");
+                let path = data_path(&config_file("op_storage", configuration_id));
+                let mut storage_config = PersistentStorageConfig::default();
+                storage_config.set_path(path.to_string_lossy().into_owned());
+                Backend::Sled(PersistentStorage::open(storage_config))
+            },
+        };
+        let mut storage = Self{ backend, promise: Arc::default() };
+        storage.promise = Arc::new(Mutex::new(storage.get_promise()));
+        storage
+    }
+
+    /// The ballot this node has promised, kept up to date after the storage is handed to omnipaxos
+    pub fn promise(&self) -> Arc<Mutex<Ballot>> {
+        self.promise.clone()
+    }
+}
+
+impl Storage<RSMCommand, OPSnapshot> for OmniPaxosStorage {
+    fn append_entry(&mut self, entry: RSMCommand) -> u64 {
+        match &mut self.backend {
+            Backend::Memory(storage) => storage.append_entry(entry),
+            Backend::Sled(storage) => storage.append_entry(entry),
+        }
+    }
+
+    fn append_entries(&mut self, entries: Vec<RSMCommand>) -> u64 {
+        match &mut self.backend {
+            Backend::Memory(storage) => storage.append_entries(entries),
+            Backend::Sled(storage) => storage.append_entries(entries),
+        }
+    }
+
+    fn append_on_prefix(&mut self, from_idx: u64, entries: Vec<RSMCommand>) -> u64 {
+        match &mut self.backend {
+            Backend::Memory(storage) => storage.append_on_prefix(from_idx, entries),
+            Backend::Sled(storage) => storage.append_on_prefix(from_idx, entries),
+        }
+    }
+
+    fn set_promise(&mut self, n_prom: Ballot) {
+        *self.promise.lock().unwrap() = n_prom;
+        match &mut self.backend {
+            Backend::Memory(storage) => storage.set_promise(n_prom),
+            Backend::Sled(storage) => storage.set_promise(n_prom),
+        }
+    }
+
+    fn set_decided_idx(&mut self, ld: u64) {
+        match &mut self.backend {
+            Backend::Memory(storage) => storage.set_decided_idx(ld),
+            Backend::Sled(storage) => storage.set_decided_idx(ld),
+        }
+    }
+
+    fn get_decided_idx(&self) -> u64 {
+        match &self.backend {
+            Backend::Memory(storage) => storage.get_decided_idx(),
+            Backend::Sled(storage) => storage.get_decided_idx(),
+        }
+    }
+
+    fn set_accepted_round(&mut self, na: Ballot) {
+        match &mut self.backend {
+            Backend::Memory(storage) => storage.set_accepted_round(na),
+            Backend::Sled(storage) => storage.set_accepted_round(na),
+        }
+    }
+
+    fn get_accepted_round(&self) -> Ballot {
+        match &self.backend {
+            Backend::Memory(storage) => storage.get_accepted_round(),
+            Backend::Sled(storage) => storage.get_accepted_round(),
+        }
+    }
+
+    fn get_entries(&self, from: u64, to: u64) -> Vec<RSMCommand> {
+        match &self.backend {
+            Backend::Memory(storage) => storage.get_entries(from, to),
+            Backend::Sled(storage) => storage.get_entries(from, to),
+        }
+    }
+
+    fn get_log_len(&self) -> u64 {
+        match &self.backend {
+            Backend::Memory(storage) => storage.get_log_len(),
+            Backend::Sled(storage) => storage.get_log_len(),
+        }
+    }
+
+    fn get_suffix(&self, from: u64) -> Vec<RSMCommand> {
+        match &self.backend {
+            Backend::Memory(storage) => storage.get_suffix(from),
+            Backend::Sled(storage) => storage.get_suffix(from),
+        }
+    }
+
+    fn get_promise(&self) -> Ballot {
+        match &self.backend {
+            Backend::Memory(storage) => storage.get_promise(),
+            Backend::Sled(storage) => storage.get_promise(),
+        }
+    }
+
+    fn set_stopsign(&mut self, s: StopSignEntry) {
+        match &mut self.backend {
+            Backend::Memory(storage) => storage.set_stopsign(s),
+            Backend::Sled(storage) => storage.set_stopsign(s),
+        }
+    }
+
+    fn get_stopsign(&self) -> Option<StopSignEntry> {
+        match &self.backend {
+            Backend::Memory(storage) => storage.get_stopsign(),
+            Backend::Sled(storage) => storage.get_stopsign(),
+        }
+    }
+
+    fn trim(&mut self, idx: u64) {
+        match &mut self.backend {
+            Backend::Memory(storage) => storage.trim(idx),
+            Backend::Sled(storage) => storage.trim(idx),
+        }
+    }
+
+    fn set_compacted_idx(&mut self, idx: u64) {
+        match &mut self.backend {
+            Backend::Memory(storage) => storage.set_compacted_idx(idx),
+            Backend::Sled(storage) => storage.set_compacted_idx(idx),
+        }
+    }
+
+    fn get_compacted_idx(&self) -> u64 {
+        match &self.backend {
+            Backend::Memory(storage) => storage.get_compacted_idx(),
+            Backend::Sled(storage) => storage.get_compacted_idx(),
+        }
+    }
+
+    fn set_snapshot(&mut self, snapshot: OPSnapshot) {
+        match &mut self.backend {
+            Backend::Memory(storage) => storage.set_snapshot(snapshot),
+            Backend::Sled(storage) => storage.set_snapshot(snapshot),
+        }
+    }
+
+    fn get_snapshot(&self) -> Option<OPSnapshot> {
+        match &self.backend {
+            Backend::Memory(storage) => storage.get_snapshot(),
+            Backend::Sled(storage) => storage.get_snapshot(),
+        }
+    }
+}
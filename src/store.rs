@@ -1,203 +1,1479 @@
 use crate::rsm::RSMCommand;
+use crate::snapshot::{OPSnapshot, SnapshotOp};
 use crate::types::*;
-use crate::{rsm, rsm::RSM};
-use omnipaxos_core::omni_paxos::CompactionErr;
+use crate::{apply, apply::{Keyspace, Versioned}, rsm, rsm::RSM, storage, watch};
+use crate::error::{Error, Result};
 use omnipaxos_core::util::LogEntry;
-use std::{sync::{Arc, Mutex}, collections::HashMap};
+use std::{env, sync::{Arc, Mutex}, collections::{BTreeMap, BTreeSet, HashMap}};
+use tokio::{time::{self, Duration, Instant}, sync::{oneshot, Notify}};
+use serde::{Serialize, Deserialize};
+
+lazy_static! {
+    static ref APPLY_INTERVAL: u64 = if let Ok(var) = env::var("APPLY_INTERVAL") {
+        var.parse().expect("APPLY_INTERVAL must be u64 in millis")
+    } else {
+        10
+    };
+
+    /// how long a request waits for its proposal to be decided, unless it sets its own timeout
+    static ref PROPOSAL_TIMEOUT: u64 = if let Ok(var) = env::var("PROPOSAL_TIMEOUT") {
+        var.parse().expect("PROPOSAL_TIMEOUT must be u64 in millis")
+    } else {
+        5000
+    };
+
+    /// how long a client session is remembered after its last command, in seconds
+    static ref SESSION_TTL: u64 = if let Ok(var) = env::var("SESSION_TTL") {
+        var.parse().expect("SESSION_TTL must be u64 in seconds")
+    } else {
+        600
+    };
+
+    /// how linearizable reads are served unless they ask for a mode: "read_index", "lease" or "log"
+    static ref LINEARIZABLE_READS: ReadMode = match env::var("LINEARIZABLE_READS").as_deref() {
+        Ok("read_index") | Err(_) => ReadMode::ReadIndex,
+        Ok("lease") => ReadMode::Lease,
+        Ok("log") => ReadMode::Log,
+        Ok(_) => panic!("LINEARIZABLE_READS must be read_index, lease or log"),
+    };
+
+    /// signalled whenever the applier has applied new entries
+    static ref APPLIED: Notify = Notify::new();
+
+    /// how often the applied state is persisted, in millis
+    static ref CHECKPOINT_INTERVAL: u64 = if let Ok(var) = env::var("CHECKPOINT_INTERVAL") {
+        var.parse().expect("CHECKPOINT_INTERVAL must be u64 in millis")
+    } else {
+        1000
+    };
+}
+
+/// longest wait between two attempts to catch up from a peer
+const CATCH_UP_MAX_BACKOFF: Duration = Duration::from_secs(5);
 
 static mut INSTANCE: Option<Arc<Mutex<Store>>> = None;
 
+/// A single version of a key, a version without a value is a tombstone
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct KeyVersion {
+    value: Option<Value>,
+    create_revision: Revision,
+    mod_revision: Revision,
+    version: u64,
+    lease: Option<LeaseId>,
+}
+
+/// Only versions with a value are current
+impl Versioned for KeyVersion {
+    fn value(&self) -> &Value {
+        self.value.as_ref().expect("tombstones are never current")
+    }
+
+    fn version(&self) -> u64 {
+        self.version
+    }
+
+    fn created(&self) -> u64 {
+        self.create_revision
+    }
+
+    fn mod_revision(&self) -> Option<Revision> {
+        Some(self.mod_revision)
+    }
+}
+
+/// A granted lease and the keys currently attached to it
+#[derive(Debug, Clone)]
+struct Lease {
+    ttl: u64,
+    keys: BTreeSet<Key>,
+    /// local, not replicated: when the leader should revoke this lease through the log
+    deadline: Instant,
+}
+
+/// The last command of a client session
+#[derive(Debug, Clone)]
+struct ClientSession {
+    seq: u64,
+    /// the result of the command with sequence number `seq`, `Duplicate` if it is not known anymore
+    result: Option<CommandResult>,
+    /// local, not replicated: when the leader should expire this session through the log
+    deadline: Instant,
+}
+
+impl ClientSession {
+    fn new(seq: u64, result: Option<CommandResult>) -> Self {
+        Self{ seq, result, deadline: Instant::now() + Duration::from_secs(*SESSION_TTL) }
+    }
+}
+
+/// Everything needed to continue from a revision without the log before it,
+/// carried from one configuration into the next
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoreState {
+    map: BTreeMap<Key, KeyVersion>,
+    /// TTL and attached keys of every lease
+    leases: HashMap<LeaseId, (u64, BTreeSet<Key>)>,
+    /// last sequence number of every client session
+    sessions: HashMap<String, u64>,
+    revision: Revision,
+}
+
+impl StoreState {
+    pub fn get(&self, key: &Key) -> Option<VersionedKeyValue> {
+        self.map.get(key).map(|v| VersionedKeyValue{
+            key: key.to_owned(),
+            value: v.value.clone().unwrap(),
+            create_revision: v.create_revision,
+            mod_revision: v.mod_revision,
+            version: v.version,
+            lease: v.lease,
+        })
+    }
+
+    pub fn leases(&self) -> &HashMap<LeaseId, (u64, BTreeSet<Key>)> {
+        &self.leases
+    }
+
+    pub fn revision(&self) -> Revision {
+        self.revision
+    }
+
+    pub fn sessions(&self) -> &HashMap<String, u64> {
+        &self.sessions
+    }
+}
+
+/// The applied state of the store, persisted so a restarted node only has to apply the log after it
+#[derive(Debug, Serialize, Deserialize)]
+struct Checkpoint {
+    config_id: u32,
+    /// only the current version of every key, the recovered store is compacted up to the checkpoint
+    map: BTreeMap<Key, KeyVersion>,
+    /// TTL and attached keys of every lease
+    leases: HashMap<LeaseId, (u64, BTreeSet<Key>)>,
+    /// last sequence number of every client session
+    sessions: HashMap<String, u64>,
+    applied_log_index: u64,
+    log_offset: u64,
+}
+
+/// The outcome of a command, for the node that proposed it
 #[derive(Debug, Clone)]
+enum CommandResult {
+    /// puts and deletes
+    Put(PutResponse),
+    CAS(CASResponse),
+    Txn(TxnResponse),
+    LeaseGrant(LeaseId),
+    /// the command was applied without any effect, e.g. a put onto a lease that was revoked
+    Failed(Error),
+    /// a retried session command whose original result is no longer known
+    Duplicate,
+}
+
+/// Tells a proposer that its command has been applied
+#[derive(Debug)]
+struct Commit {
+    result: Option<CommandResult>,
+    /// the global log index the command was applied at, reads with it as `min_index` see the command
+    index: u64,
+}
+
+/// Multi-version key-value store. The revision of the store is its applied global log index,
+/// so the entry at global log index `i` is applied at revision `i + 1`. The global index counts
+/// the entries of all previous configurations, including their StopSigns.
+#[derive(Debug)]
 struct Store {
-    map: HashMap<Key, Value>,
+    /// all retained versions of a key, ordered by mod_revision
+    map: BTreeMap<Key, Vec<KeyVersion>>,
+    leases: HashMap<LeaseId, Lease>,
+    sessions: HashMap<String, ClientSession>,
+    /// local proposals waiting for their command to be applied
+    pending: HashMap<(u64, u64), oneshot::Sender<Result<Commit>>>,
+    /// applied index into the log of the current configuration
     applied_log_index: u64,
+    /// revision at which the log of the current configuration starts
+    log_offset: u64,
+    /// the oldest revision that can still be read
+    compact_revision: Revision,
+    /// set once entries this replica still has to apply were trimmed from the log,
+    /// it then needs the state of a peer that has applied at least up to this global index
+    catch_up_to: Option<u64>,
 }
 
 impl Store {
+    fn new() -> Self {
+        Store{
+            map: BTreeMap::new(),
+            leases: HashMap::new(),
+            sessions: HashMap::new(),
+            pending: HashMap::new(),
+            applied_log_index: 0,
+            log_offset: 0,
+            compact_revision: 0,
+            catch_up_to: None,
+        }
+    }
+
     /// Get the singleton Store instance
     fn instance() -> Arc<Mutex<Self>> {
         unsafe {
             if let Some(ref store) = INSTANCE {
                 store.clone()
             } else {
-                let store = Arc::new(Mutex::new(Store{
-                    map: HashMap::new(),
-                    applied_log_index: 0,
-                }));
+                let store = Arc::new(Mutex::new(Store::new()));
                 INSTANCE = Some(store.clone());
                 store
             }
         }
     }
 
-    /// Call this before every read to stay up to date
+    fn revision(&self) -> Revision {
+        self.log_offset + self.applied_log_index
+    }
+
+    /// Applies newly decided entries and resolves the proposals waiting for them,
+    /// only ever called by the applier task, reads serve whatever has been applied
     fn apply_decided_entries(&mut self) {
-        if let Some(entries) = RSM::instance().lock().unwrap().omnipaxos.read_decided_suffix(self.applied_log_index) {
+        let unlocked = RSM::instance();
+        let mut rsm = unlocked.lock().unwrap();
+        if let Some(entries) = rsm.omnipaxos.read_decided_suffix(self.applied_log_index) {
             for entry in entries {
-                self.applied_log_index += 1;
                 match entry {
-                    LogEntry::Decided(cmd) => { 
-                        match cmd {
-                            RSMCommand::Put((_, kv)) => { self.map.insert(kv.key, kv.value); },
-                            RSMCommand::CAS((_, kv, exp_val)) => {
-                                if let Some(old_val) = self.map.get(&kv.key) {
-                                    if *old_val == exp_val {
-                                        self.map.insert(kv.key, kv.value);
-                                    }
-                                }
-                            },
-                            RSMCommand::Delete((_, key)) => { self.map.remove(&key); },
-                            RSMCommand::LinearizableRead(_) => (),
-                            RSMCommand::Clear(_) => { self.map.clear(); },
+                    LogEntry::Decided(cmd) => {
+                        self.applied_log_index += 1;
+                        let id = cmd.get_id();
+                        let revision = self.revision();
+                        let (events, result) = self.apply_command(cmd, revision);
+                        self.resolve(id, result, revision);
+                        if !events.is_empty() {
+                            watch::notify(events);
                         }
                     },
-                    LogEntry::Snapshotted(entry) => {
-                        if entry.snapshot.clear {
-                            self.map.clear();
+                    LogEntry::Snapshotted(entry) => self.restore_snapshot(entry.snapshot, entry.trimmed_idx),
+                    LogEntry::Undecided(x) => { panic!("read undecided log entry: {:?}", x)},
+                    LogEntry::StopSign(ss) => {
+                        self.applied_log_index += 1;
+                        let revision = self.revision();
+                        let config_id = ss.config_id;
+                        rsm.migrate(ss, revision, self.state());
+                        // nothing that is still pending can be decided in the old configuration anymore
+                        for (_, tx) in self.pending.drain() {
+                            let _ = tx.send(Err(Error::ProposalDropped));
                         }
-                        for (_, v) in entry.snapshot.snapshotted.into_iter() {
-                            for cmd in v {
-                                match cmd {
-                                    RSMCommand::Put((_, kv)) => { self.map.insert(kv.key, kv.value); },
-                                    RSMCommand::CAS((_, kv, exp_val)) => {
-                                        if let Some(old_val) = self.map.get(&kv.key) {
-                                            if *old_val == exp_val {
-                                                self.map.insert(kv.key, kv.value);
-                                            }
-                                        }
-                                    },
-                                    RSMCommand::Delete((_, key)) => { self.map.remove(&key); },
-                                    RSMCommand::Clear(_) => (),
-                                    RSMCommand::LinearizableRead(_) => (),
-                                }
+                        // a removed node keeps serving reads from the old log
+                        if !rsm.removed {
+                            self.log_offset = revision;
+                            self.applied_log_index = 0;
+                            // a node restarted in an older configuration continues from the checkpoint of the new one
+                            if let Some(checkpoint) = read_checkpoint(config_id).filter(|checkpoint| checkpoint.log_offset == revision) {
+                                println!("recovering from checkpoint at revision {}", checkpoint.log_offset + checkpoint.applied_log_index);
+                                self.recover(checkpoint);
                             }
                         }
                     },
-                    LogEntry::Undecided(x) => { panic!("read undecided log entry: {:?}", x)},
-                    LogEntry::StopSign(_) => { todo!() },
-                    LogEntry::Trimmed(_) => { todo!() },
+                    LogEntry::Trimmed(trimmed_idx) => {
+                        self.catch_up_to = Some(self.log_offset + trimmed_idx);
+                        break
+                    },
+                }
+            }
+        }
+        // the first entry of a configuration carries over the store, after it we can take proposals
+        if !rsm.accepting && !rsm.removed && self.applied_log_index > 0 {
+            rsm.log_offset = self.log_offset;
+            rsm.accepting = true;
+        }
+        // we keep history only as far back as the log itself, but never drop what is still the current state,
+        // e.g. while this node has to catch up with a log that was trimmed past what it applied
+        self.compact((self.log_offset + rsm.omnipaxos.get_compacted_idx()).min(self.revision()));
+    }
+
+    /// Applies a single command at the given revision
+    /// returns the resulting change events, all at that revision, and the result for the proposer
+    fn apply_command(&mut self, cmd: RSMCommand, revision: Revision) -> (Vec<WatchEvent>, Option<CommandResult>) {
+        let event = |kind, key, value| WatchEvent{ kind, key, value, revision };
+        let events = match cmd {
+            RSMCommand::Put(_) | RSMCommand::CAS(_) | RSMCommand::Delete(_) | RSMCommand::Txn(_) => return self.apply_key_command(&cmd, revision),
+            RSMCommand::LinearizableRead(_) => vec![],
+            RSMCommand::Clear(_) => {
+                self.clear(revision);
+                vec![event(EventKind::Clear, None, None)]
+            },
+            RSMCommand::LeaseGrant((_, ttl)) => {
+                let deadline = Instant::now() + Duration::from_secs(ttl);
+                self.leases.insert(revision, Lease{ ttl, keys: BTreeSet::new(), deadline });
+                return (vec![], Some(CommandResult::LeaseGrant(revision)))
+            },
+            RSMCommand::LeaseKeepAlive((_, id)) => {
+                if let Some(lease) = self.leases.get_mut(&id) {
+                    lease.deadline = Instant::now() + Duration::from_secs(lease.ttl);
                 }
+                vec![]
+            },
+            RSMCommand::LeaseRevoke((_, id)) => {
+                if let Some(lease) = self.leases.remove(&id) {
+                    for key in lease.keys.iter() {
+                        self.write(key.clone(), None, None, revision);
+                    }
+                    lease.keys.into_iter().map(|key| event(EventKind::Delete, Some(key), None)).collect()
+                } else {
+                    vec![]
+                }
+            },
+            RSMCommand::Migrate((_, state)) => {
+                // the state is proposed again to every new leader, only the first entry is used
+                if revision == self.log_offset + 1 {
+                    self.load(state);
+                }
+                vec![]
+            },
+            RSMCommand::Session((session, cmd)) => {
+                // a retried command is not applied again, it gets the result of its first attempt
+                if let Some(known) = self.sessions.get_mut(&session.id) {
+                    if session.seq <= known.seq {
+                        known.deadline = Instant::now() + Duration::from_secs(*SESSION_TTL);
+                        let result = if session.seq == known.seq { known.result.clone() } else { Some(CommandResult::Duplicate) };
+                        return (vec![], result)
+                    }
+                }
+                let (events, result) = self.apply_command(*cmd, revision);
+                self.sessions.insert(session.id, ClientSession::new(session.seq, result.clone()));
+                return (events, result)
+            },
+            RSMCommand::ExpireSessions((_, ids)) => {
+                for id in ids {
+                    self.sessions.remove(&id);
+                }
+                vec![]
+            },
+        };
+        (events, None)
+    }
+
+    /// Applies a put, delete, CAS or transaction with the command semantics shared with snapshots
+    fn apply_key_command(&mut self, cmd: &RSMCommand, revision: Revision) -> (Vec<WatchEvent>, Option<CommandResult>) {
+        let mut applying = Applying{ store: self, events: vec![], responses: vec![] };
+        let outcome = apply::apply(&mut applying, cmd, revision).expect("the store knows every key and lease");
+        let Applying{ mut events, mut responses, .. } = applying;
+        let result = match (cmd, outcome) {
+            // keys can only be attached to leases that exist when the command is applied
+            (_, Err(err)) => CommandResult::Failed(err),
+            (RSMCommand::CAS((_, kv, _)), Ok(succeeded)) => {
+                for event in events.iter_mut() {
+                    event.kind = EventKind::CAS;
+                }
+                // a CAS that did not succeed reports the current key
+                let prev_kv = match responses.pop() {
+                    Some(TxnOpResponse::Put(resp)) => resp.prev_kv,
+                    _ => self.current_kv(&kv.key),
+                };
+                CommandResult::CAS(CASResponse{ succeeded, prev_kv, revision })
+            },
+            (RSMCommand::Txn(_), Ok(succeeded)) => CommandResult::Txn(TxnResponse{ succeeded, responses, revision }),
+            (_, Ok(_)) => match responses.pop() {
+                Some(TxnOpResponse::Put(resp)) | Some(TxnOpResponse::Delete(resp)) => CommandResult::Put(resp),
+                _ => unreachable!("puts and deletes write their key"),
+            },
+        };
+        (events, Some(result))
+    }
+
+    /// Replaces the whole state with a snapshot of the log up to `trimmed_idx`
+    fn restore_snapshot(&mut self, snapshot: OPSnapshot, trimmed_idx: u64) {
+        self.map.clear();
+        self.leases.clear();
+        self.sessions.clear();
+        let base = trimmed_idx.saturating_sub(snapshot.len);
+        // leases and transactions tie commands on different keys together, so everything is replayed in log order
+        for (offset, op) in snapshot.replay() {
+            // loading the migrated state moves the log offset, so it is read again for every step
+            let start = self.log_offset + base + 1;
+            match op {
+                SnapshotOp::Reset(key, state) => {
+                    let version = state.map(|state| KeyVersion{
+                        value: Some(state.value),
+                        create_revision: state.create_revision.unwrap_or(start + state.create_offset),
+                        mod_revision: state.mod_revision.unwrap_or(start + state.mod_offset),
+                        version: state.version,
+                        lease: state.lease,
+                    });
+                    self.reset(key, version);
+                },
+                SnapshotOp::Apply(cmd) => {
+                    let id = cmd.get_id();
+                    let (_, result) = self.apply_command(cmd, start + offset);
+                    self.resolve(id, result, start + offset);
+                },
+                SnapshotOp::Clear => self.clear(start + offset),
+                // a session whose last command was dropped from the snapshot still must not apply it again
+                SnapshotOp::Session(id, seq) => if self.sessions.get(&id).is_none_or(|known| known.seq < seq) {
+                    self.sessions.insert(id, ClientSession::new(seq, Some(CommandResult::Duplicate)));
+                },
             }
         }
+        self.applied_log_index = trimmed_idx;
+        self.compact_revision = self.revision();
+        // watchers cannot be told what happened in between
+        watch::reset(self.revision());
+    }
+
+    /// Hands the result of an applied command to its proposer, if it is waiting on this node
+    fn resolve(&mut self, id: (u64, u64), result: Option<CommandResult>, index: u64) {
+        if let Some(tx) = self.pending.remove(&id) {
+            let _ = tx.send(Ok(Commit{ result, index }));
+        }
+    }
+
+    /// The current state, for carrying it over into a new configuration
+    fn state(&self) -> StoreState {
+        StoreState{
+            map: self.map.keys().filter_map(|key| self.current(key).map(|v| (key.clone(), v.clone()))).collect(),
+            leases: self.leases.iter().map(|(id, lease)| (*id, (lease.ttl, lease.keys.clone()))).collect(),
+            sessions: self.sessions.iter().map(|(id, session)| (id.clone(), session.seq)).collect(),
+            revision: self.revision(),
+        }
+    }
+
+    /// Replaces the whole state with one carried over from the previous configuration
+    fn load(&mut self, state: StoreState) {
+        let revision = state.revision;
+        self.load_state(state);
+        self.log_offset = revision;
+        self.compact_revision = revision;
+        watch::compact(revision);
+    }
+
+    /// Replaces the whole state with the state of a peer that has applied further in the current configuration
+    fn install(&mut self, state: StoreState) {
+        let revision = state.revision;
+        self.load_state(state);
+        self.applied_log_index = revision - self.log_offset;
+        self.compact_revision = revision;
+        self.catch_up_to = None;
+        // watchers cannot be told what happened in between
+        watch::reset(revision);
+    }
+
+    /// Everything a restarted node needs to continue applying the log where this store is
+    fn checkpoint(&self, config_id: u32) -> Checkpoint {
+        let state = self.state();
+        Checkpoint{
+            config_id,
+            map: state.map,
+            leases: state.leases,
+            sessions: state.sessions,
+            applied_log_index: self.applied_log_index,
+            log_offset: self.log_offset,
+        }
+    }
+
+    /// Continues from a persisted checkpoint, the log after it is applied as usual
+    fn recover(&mut self, checkpoint: Checkpoint) {
+        let revision = checkpoint.log_offset + checkpoint.applied_log_index;
+        self.load_state(StoreState{
+            map: checkpoint.map,
+            leases: checkpoint.leases,
+            sessions: checkpoint.sessions,
+            revision,
+        });
+        self.applied_log_index = checkpoint.applied_log_index;
+        self.log_offset = checkpoint.log_offset;
+        // older versions are not checkpointed
+        self.compact_revision = revision;
+        watch::reset(revision);
+    }
+
+    /// Replaces keys, leases and sessions
+    fn load_state(&mut self, state: StoreState) {
+        self.map = state.map.into_iter().map(|(key, v)| (key, vec![v])).collect();
+        let now = Instant::now();
+        self.leases = state.leases.into_iter()
+            .map(|(id, (ttl, keys))| (id, Lease{ ttl, keys, deadline: now + Duration::from_secs(ttl) }))
+            .collect();
+        self.sessions = state.sessions.into_iter()
+            .map(|(id, seq)| (id, ClientSession::new(seq, Some(CommandResult::Duplicate))))
+            .collect();
+    }
+
+    /// Adds a new version of a key, `None` deletes the key, and moves the key to its new lease
+    /// returns false if there was nothing to delete
+    fn write(&mut self, key: Key, value: Option<Value>, lease: Option<LeaseId>, revision: Revision) -> bool {
+        let versions = self.map.entry(key.clone()).or_default();
+        let prev = versions.last().filter(|v| v.value.is_some());
+        let prev_lease = prev.and_then(|v| v.lease);
+        let new_version = match (prev, value.is_some()) {
+            (None, false) => return false,
+            (Some(_), false) => KeyVersion{ value, create_revision: 0, mod_revision: revision, version: 0, lease: None },
+            (prev, true) => {
+                let (create_revision, version) = apply::written(prev, revision);
+                KeyVersion{ value, create_revision, mod_revision: revision, version, lease }
+            },
+        };
+        let new_lease = new_version.lease;
+        versions.push(new_version);
+
+        if let Some(old) = prev_lease.and_then(|id| self.leases.get_mut(&id)) {
+            old.keys.remove(&key);
+        }
+        if let Some(new) = new_lease.and_then(|id| self.leases.get_mut(&id)) {
+            new.keys.insert(key);
+        }
+        true
+    }
+
+    /// Deletes all keys
+    fn clear(&mut self, revision: Revision) {
+        let keys: Vec<Key> = self.map.keys().cloned().collect();
+        for key in keys {
+            self.write(key, None, None, revision);
+        }
+    }
+
+    /// Replaces a key with a version restored from a snapshot, `None` deletes it,
+    /// the history of the key is dropped
+    fn reset(&mut self, key: Key, version: Option<KeyVersion>) {
+        let lease = self.current(&key).and_then(|v| v.lease);
+        if let Some(lease) = lease.and_then(|id| self.leases.get_mut(&id)) {
+            lease.keys.remove(&key);
+        }
+        if let Some(lease) = version.as_ref().and_then(|v| v.lease).and_then(|id| self.leases.get_mut(&id)) {
+            lease.keys.insert(key.clone());
+        }
+        match version {
+            Some(version) => { self.map.insert(key, vec![version]); },
+            None => { self.map.remove(&key); },
+        }
+    }
+
+    /// The latest version of a key, if the key currently exists
+    fn current(&self, key: &Key) -> Option<&KeyVersion> {
+        self.map.get(key).and_then(|versions| versions.last()).filter(|v| v.value.is_some())
+    }
+
+    fn current_kv(&self, key: &Key) -> Option<VersionedKeyValue> {
+        self.current(key).map(|v| VersionedKeyValue{
+            key: key.to_owned(),
+            value: v.value.clone().unwrap(),
+            create_revision: v.create_revision,
+            mod_revision: v.mod_revision,
+            version: v.version,
+            lease: v.lease,
+        })
+    }
+
+    /// Drops all versions that are overwritten at `revision`
+    fn compact(&mut self, revision: Revision) {
+        if revision <= self.compact_revision {
+            return
+        }
+        self.map.retain(|_, versions| {
+            let visible = versions.iter().rposition(|v| v.mod_revision <= revision);
+            if let Some(i) = visible {
+                versions.drain(..i);
+                if versions[0].value.is_none() {
+                    versions.remove(0);
+                }
+            }
+            !versions.is_empty()
+        });
+        self.compact_revision = revision;
+        watch::compact(revision);
+    }
+
+    /// Leases that the leader should revoke now, their deadlines are pushed back by
+    /// another TTL, so a lost revocation is retried later instead of on every tick
+    fn expired_leases(&mut self) -> Vec<LeaseId> {
+        if !rsm::is_leader() {
+            return vec![]
+        }
+        self.due_leases(Instant::now())
+    }
+
+    /// Leases whose deadline has passed at `now`
+    fn due_leases(&mut self, now: Instant) -> Vec<LeaseId> {
+        let mut expired = vec![];
+        for (id, lease) in self.leases.iter_mut() {
+            if lease.deadline <= now {
+                lease.deadline = now + Duration::from_secs(lease.ttl);
+                expired.push(*id);
+            }
+        }
+        expired
+    }
+
+    /// Client sessions that the leader should expire now, pushed back like expired leases
+    fn expired_sessions(&mut self) -> Vec<String> {
+        if !rsm::is_leader() {
+            return vec![]
+        }
+        let now = Instant::now();
+        let mut expired = vec![];
+        for (id, session) in self.sessions.iter_mut() {
+            if session.deadline <= now {
+                session.deadline = now + Duration::from_secs(*SESSION_TTL);
+                expired.push(id.clone());
+            }
+        }
+        expired
+    }
+
+    /// The version of a key that was visible at the given revision
+    fn version_at(versions: &[KeyVersion], revision: Revision) -> Option<&KeyVersion> {
+        versions.iter().rev().find(|v| v.mod_revision <= revision).filter(|v| v.value.is_some())
+    }
+
+    /// Whether the given revision can be read
+    fn check_revision(&self, revision: Revision) -> Result<()> {
+        if revision < self.compact_revision {
+            Err(Error::Compacted(self.compact_revision))
+        } else if revision > self.revision() {
+            Err(Error::InvalidRequest(format!("revision {} is newer than the current revision {}", revision, self.revision())))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Reads a key as it was at the given revision
+    fn read(&self, key: &Key, revision: Revision) -> Result<GetResponse> {
+        self.check_revision(revision)?;
+        let version = self.map.get(key).and_then(|versions| Self::version_at(versions, revision));
+        Ok(match version {
+            Some(v) => GetResponse{
+                key: key.to_owned(),
+                value: v.value.clone(),
+                create_revision: v.create_revision,
+                mod_revision: v.mod_revision,
+                version: v.version,
+                lease: v.lease,
+                revision,
+                applied_index: self.revision(),
+            },
+            None => GetResponse{ revision, applied_index: self.revision(), ..GetResponse::empty(key.to_owned()) },
+        })
+    }
+
+    /// Reads a range of keys as they were at the given revision, in key order
+    fn range(&self, req: &RangeRequest, revision: Revision) -> Result<RangeResponse> {
+        self.check_revision(revision)?;
+        Ok(self.range_at(req, revision))
+    }
+
+    fn range_at(&self, req: &RangeRequest, revision: Revision) -> RangeResponse {
+        // all ranges start at `req.key`, so we only need to know where they end
+        let in_range = |key: &Key| -> bool {
+            if req.prefix {
+                return key.starts_with(&req.key)
+            }
+            match req.range_end.as_deref() {
+                None => *key == req.key,
+                Some("\0") => true,
+                Some(end) => key.as_str() < end,
+            }
+        };
+        let matches: Vec<VersionedKeyValue> = self.map.range(req.key.clone()..)
+            .take_while(|&(key, _)| in_range(key))
+            .filter_map(|(key, versions)| Self::version_at(versions, revision).map(|v| VersionedKeyValue{
+                key: key.clone(),
+                value: if req.keys_only { Value::new() } else { v.value.clone().unwrap() },
+                create_revision: v.create_revision,
+                mod_revision: v.mod_revision,
+                version: v.version,
+                lease: v.lease,
+            }))
+            .collect();
+
+        let count = matches.len() as u64;
+        let more = req.limit > 0 && count > req.limit;
+        let kvs = if req.count_only {
+            vec![]
+        } else if req.limit > 0 {
+            matches.into_iter().take(req.limit as usize).collect()
+        } else {
+            matches
+        };
+        RangeResponse{ kvs, count, more, revision, applied_index: self.revision() }
     }
 }
 
-/// Sequentially consistent read
-pub fn get(key: &Key) -> Option<Value> {
-    let unlocked = Store::instance();
-    let mut store = unlocked.lock().unwrap();
-    store.apply_decided_entries();
-    store.map.get(key).map(|x| x.to_owned())
+/// The store while it applies a key command, collecting its change events and the responses of a transaction
+struct Applying<'a> {
+    store: &'a mut Store,
+    events: Vec<WatchEvent>,
+    responses: Vec<TxnOpResponse>,
+}
+
+impl Keyspace for Applying<'_> {
+    type State = KeyVersion;
+
+    fn state(&self, key: &Key) -> Option<Option<&KeyVersion>> {
+        Some(self.store.current(key))
+    }
+
+    fn lease_exists(&self, id: LeaseId) -> Option<bool> {
+        Some(self.store.leases.contains_key(&id))
+    }
+
+    fn write(&mut self, key: &Key, value: Option<(&Value, Option<LeaseId>)>, revision: Revision) {
+        let resp = PutResponse{ prev_kv: self.store.current_kv(key), revision };
+        match value {
+            Some((value, lease)) => {
+                self.store.write(key.clone(), Some(value.clone()), lease, revision);
+                self.events.push(WatchEvent{ kind: EventKind::Put, key: Some(key.clone()), value: Some(value.clone()), revision });
+                self.responses.push(TxnOpResponse::Put(resp));
+            },
+            None => {
+                if self.store.write(key.clone(), None, None, revision) {
+                    self.events.push(WatchEvent{ kind: EventKind::Delete, key: Some(key.clone()), value: None, revision });
+                }
+                self.responses.push(TxnOpResponse::Delete(resp));
+            },
+        }
+    }
+
+    fn range(&mut self, req: &RangeRequest, revision: Revision) {
+        let resp = self.store.range_at(req, revision);
+        self.responses.push(TxnOpResponse::Range(resp));
+    }
+}
+
+/// The applier task, the only place where decided entries are applied to the store. It wakes up whenever
+/// omnipaxos made progress, and at least every APPLY_INTERVAL, when the leader also revokes expired leases.
+pub async fn run() {
+    if storage::persistent() {
+        recover();
+        // persist the store, so a restart does not have to apply the whole log again
+        tokio::spawn(checkpoints());
+    }
+    let mut apply_interval = time::interval(Duration::from_millis(*APPLY_INTERVAL));
+    // while no peer can be caught up from, the attempts back off
+    let mut catch_up_backoff = Duration::from_millis(*APPLY_INTERVAL);
+    let mut next_catch_up = Instant::now();
+    loop {
+        tokio::select! {
+            _ = apply_interval.tick() => (),
+            _ = rsm::progress() => (),
+        }
+        let (expired, expired_sessions, catch_up_to) = {
+            let unlocked = Store::instance();
+            let mut store = unlocked.lock().unwrap();
+            store.apply_decided_entries();
+            (store.expired_leases(), store.expired_sessions(), store.catch_up_to)
+        };
+        match catch_up_to {
+            Some(index) if Instant::now() >= next_catch_up => {
+                if install_from_peer(index).await {
+                    catch_up_backoff = Duration::from_millis(*APPLY_INTERVAL);
+                } else {
+                    println!("no peer has applied up to revision {}, retrying in {}ms", index, catch_up_backoff.as_millis());
+                    next_catch_up = Instant::now() + catch_up_backoff;
+                    catch_up_backoff = (catch_up_backoff * 2).min(CATCH_UP_MAX_BACKOFF);
+                }
+            },
+            Some(_) => (),
+            None => catch_up_backoff = Duration::from_millis(*APPLY_INTERVAL),
+        }
+        APPLIED.notify_waiters();
+        for id in expired {
+            tokio::spawn(async move {
+                let _ = propose(RSMCommand::new_lease_revoke(id), &ProposalOptions::default()).await;
+            });
+        }
+        if !expired_sessions.is_empty() {
+            tokio::spawn(async move {
+                let _ = propose(RSMCommand::new_expire_sessions(expired_sessions), &ProposalOptions::default()).await;
+            });
+        }
+    }
+}
+
+/// Replaces the store with the state of the first peer that has applied at least up to `index`,
+/// for replicas that fell behind a trim of the log. Returns false if no peer could provide it.
+async fn install_from_peer(index: u64) -> bool {
+    let (config_id, _) = rsm::config();
+    for addr in rsm::peer_addrs() {
+        let url = format!("http://{}/state?config={}", addr, config_id);
+        let request = reqwest::Client::new().get(url).timeout(Duration::from_millis(*PROPOSAL_TIMEOUT));
+        let state = match request.send().await {
+            Ok(resp) => resp.json::<Option<StoreState>>().await.ok().flatten(),
+            Err(_) => None,
+        };
+        if let Some(state) = state.filter(|state| state.revision >= index) {
+            let unlocked = Store::instance();
+            let mut store = unlocked.lock().unwrap();
+            if store.catch_up_to.is_some() && state.revision > store.revision() {
+                println!("catching up to revision {} from {}", state.revision, addr);
+                store.install(state);
+            }
+            return true
+        }
+    }
+    false
+}
+
+/// Reads the last checkpoint taken in a configuration, which has its own file just like its own log
+fn read_checkpoint(config_id: u32) -> Option<Checkpoint> {
+    if !storage::persistent() {
+        return None
+    }
+    let bytes = std::fs::read(storage::data_path(&storage::config_file("store_checkpoint", config_id))).ok()?;
+    match serde_json::from_slice::<Checkpoint>(&bytes) {
+        Ok(checkpoint) => Some(checkpoint),
+        Err(err) => {
+            println!("ignoring unreadable checkpoint: {}", err);
+            None
+        },
+    }
 }
 
-/// linearizable read
-pub async fn linearizable_get(key: &Key) -> Result<Option<Value>, ()> {
-    rsm::append(RSMCommand::new_linearizable_read()).await?;
-    Ok(get(key))
+/// Loads the last checkpoint of the configuration this node starts in
+fn recover() {
+    if let Some(checkpoint) = read_checkpoint(rsm::config().0) {
+        let unlocked = Store::instance();
+        let mut store = unlocked.lock().unwrap();
+        println!("recovering from checkpoint at revision {}", checkpoint.log_offset + checkpoint.applied_log_index);
+        store.recover(checkpoint);
+    }
 }
 
-/// Takes a previous value that was read before an operation and updates it with
-/// the new commands that were decided during the operation
-fn get_prev_value_after_decide(key: &Key, mut prev_val: Option<Value>, prev_decided_idx: u64, new_decided_idx: u64) -> Option<Value> {
-    if let Some(entries) = RSM::instance().lock().unwrap().omnipaxos.read_decided_suffix(prev_decided_idx) {
-        for (i, entry) in entries.iter().enumerate() {
-            if prev_decided_idx + (i as u64) == new_decided_idx {
-                break // only read until the operation's entry's index
+/// Persists the applied state whenever it changed, so recovering after a restart
+/// only applies the entries decided since the last checkpoint
+async fn checkpoints() {
+    let mut interval = time::interval(Duration::from_millis(*CHECKPOINT_INTERVAL));
+    let mut last = None;
+    loop {
+        interval.tick().await;
+        let checkpoint = {
+            let unlocked = Store::instance();
+            let store = unlocked.lock().unwrap();
+            // a store that fell behind a trim waits for the state of a peer first
+            if store.catch_up_to.is_some() || last == Some(store.revision()) {
+                continue
             }
-            match entry {
-                LogEntry::Decided(cmd) => {
-                    match cmd {
-                        RSMCommand::LinearizableRead(_) => (),
-                        RSMCommand::Clear(_) => prev_val = None,
-                        RSMCommand::Put((_, kv)) => {
-                            if kv.key == *key {
-                                prev_val = Some(kv.value.clone());
-                            }
-                        },
-                        RSMCommand::Delete((_, del_key)) => {
-                            if *del_key == *key {
-                                prev_val = None;
-                            }
-                        },
-                        RSMCommand::CAS((_, kv, exp_val)) => {
-                            if let Some(ref prev) = prev_val {
-                                if kv.key == *key && *prev == *exp_val {
-                                    prev_val = Some(kv.value.clone());
-                                }
-                            }
-                        },
-                    }
-                },
-                LogEntry::Snapshotted(entry) => {
-                    if entry.snapshot.clear {
-                        prev_val = None;
+            last = Some(store.revision());
+            store.checkpoint(rsm::config().0)
+        };
+        let written = tokio::task::spawn_blocking(move || write_checkpoint(&checkpoint)).await
+            .unwrap_or_else(|err| Err(Error::Storage(err.to_string())));
+        if let Err(err) = written {
+            println!("failed to write checkpoint: {}", err);
+            last = None;
+        }
+    }
+}
+
+fn write_checkpoint(checkpoint: &Checkpoint) -> Result<()> {
+    let bytes = serde_json::to_vec(checkpoint).map_err(|err| Error::Storage(err.to_string()))?;
+    storage::write_atomically(&storage::config_file("store_checkpoint", checkpoint.config_id), &bytes)
+}
+
+/// The state of this replica, for a peer of the same configuration that fell behind a trim
+pub fn state(config_id: u32) -> Option<StoreState> {
+    let unlocked = Store::instance();
+    let store = unlocked.lock().unwrap();
+    if store.catch_up_to.is_some() || rsm::config().0 != config_id {
+        return None
+    }
+    Some(store.state())
+}
+
+/// The global log index this replica has applied up to
+pub fn applied_index() -> u64 {
+    Store::instance().lock().unwrap().revision()
+}
+
+/// Reads a key from what this replica has applied so far
+pub fn get(key: &Key) -> Result<GetResponse> {
+    let unlocked = Store::instance();
+    let store = unlocked.lock().unwrap();
+    store.read(key, store.revision())
+}
+
+/// Reads a key at a past revision
+/// fails if the revision has been compacted or does not exist yet
+pub fn get_at(key: &Key, revision: Revision) -> Result<GetResponse> {
+    let unlocked = Store::instance();
+    let store = unlocked.lock().unwrap();
+    store.read(key, revision)
+}
+
+/// Reads a range of keys from what this replica has applied so far
+/// fails if the requested revision has been compacted or does not exist yet
+pub fn range(req: &RangeRequest) -> Result<RangeResponse> {
+    let unlocked = Store::instance();
+    let store = unlocked.lock().unwrap();
+    let revision = req.revision.unwrap_or(store.revision());
+    store.range(req, revision)
+}
+
+/// Waits until the store has applied everything up to a global log index
+async fn wait_applied(index: u64) {
+    loop {
+        let applied = APPLIED.notified();
+        tokio::pin!(applied);
+        // register before checking, so an apply right after the check is not missed
+        applied.as_mut().enable();
+        let revision = Store::instance().lock().unwrap().revision();
+        if revision >= index {
+            return
+        }
+        applied.await;
+    }
+}
+
+/// Waits until this replica can serve a read with the requested consistency, sequential by default.
+/// A linearizable read is served according to LINEARIZABLE_READS, unless it asks for a mode.
+pub async fn catch_up(params: &ConsistencyParams, timeout: Option<Duration>) -> Result<()> {
+    let timeout = timeout.unwrap_or(Duration::from_millis(*PROPOSAL_TIMEOUT));
+    let via_log = ProposalOptions{ timeout: Some(timeout), session: None };
+    let catch_up = async {
+        if let Some(min_index) = params.min_index {
+            wait_applied(min_index).await;
+        }
+        match params.consistency.unwrap_or(Consistency::Sequential) {
+            Consistency::Local => (),
+            Consistency::Sequential => wait_applied(rsm::decided_index()).await,
+            Consistency::BoundedStaleness => {
+                if params.max_lag.is_none() && params.max_lag_ms.is_none() {
+                    return Err(Error::InvalidRequest("bounded staleness needs max_lag or max_lag_ms".to_owned()))
+                }
+                wait_applied(rsm::decided_index()).await;
+                if let Some(max_lag_ms) = params.max_lag_ms {
+                    match rsm::since_leader_contact() {
+                        Some(since) if since <= Duration::from_millis(max_lag_ms) => (),
+                        Some(since) => return Err(Error::Stale(format!("last heard from the leader {}ms ago", since.as_millis()))),
+                        None => return Err(Error::NoLeader),
                     }
-                    if let Some(v) = entry.snapshot.snapshotted.get(key) {
-                        for cmd in v {
-                            match cmd {
-                                RSMCommand::LinearizableRead(_) => (),
-                                RSMCommand::Clear(_) => (),
-                                RSMCommand::Put((_, kv)) => { prev_val = Some(kv.value.clone()); },
-                                RSMCommand::Delete(_) => { prev_val = None; },
-                                RSMCommand::CAS((_, kv, exp_val)) => {
-                                    if kv.value == *exp_val {
-                                        prev_val = Some(kv.value.clone());
-                                    }
-                                },
-                            }
-                        }
+                }
+                if let Some(max_lag) = params.max_lag {
+                    let leader_index = rsm::leader_decided_index().await?;
+                    let applied = Store::instance().lock().unwrap().revision();
+                    let lag = leader_index.saturating_sub(applied);
+                    if lag > max_lag {
+                        return Err(Error::Stale(format!("{} entries behind the leader", lag)))
                     }
+                }
+            },
+            Consistency::Linearizable => match params.mode.unwrap_or(*LINEARIZABLE_READS) {
+                ReadMode::ReadIndex => wait_applied(rsm::read_index().await?).await,
+                ReadMode::Lease => match rsm::lease_read_index() {
+                    Some(index) => wait_applied(index).await,
+                    None => { propose(RSMCommand::new_linearizable_read(), &via_log).await?; },
                 },
-                _ => (),
-            }
+                ReadMode::Log => { propose(RSMCommand::new_linearizable_read(), &via_log).await?; },
+            },
         }
+        Ok(())
+    };
+    time::timeout(timeout, catch_up).await.unwrap_or(Err(Error::Timeout))
+}
+
+/// How a request wants its command to be proposed
+#[derive(Debug, Clone, Default)]
+pub struct ProposalOptions {
+    /// how long to wait for the command to be applied, PROPOSAL_TIMEOUT by default
+    pub timeout: Option<Duration>,
+    /// the client session and sequence number that make retries of the command safe
+    pub session: Option<Session>,
+}
+
+/// Forgets a pending proposal once its proposer stops waiting for it,
+/// also when the request is cancelled because the client went away
+struct PendingGuard((u64, u64));
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        Store::instance().lock().unwrap().pending.remove(&self.0);
     }
-    prev_val
 }
 
-/// Inserts into the replicated store
-/// returns the previous value of this key on success
-pub async fn put(kv: KeyValue) -> Result<Option<KeyValue>,()> {
-    let mut prev_value = get(&kv.key);
-    let prev_idx = RSM::instance().lock().unwrap().omnipaxos.get_decided_idx();
-    let idx = rsm::append(RSMCommand::new_put(kv.clone())).await?;
+/// Appends a command and waits until the applier has applied it, or until the timeout
+/// (PROPOSAL_TIMEOUT by default) runs out, in which case the command may still be applied later
+async fn propose(cmd: RSMCommand, opts: &ProposalOptions) -> Result<Commit> {
+    let timeout = opts.timeout.unwrap_or(Duration::from_millis(*PROPOSAL_TIMEOUT));
+    let cmd = cmd.with_session(opts.session.clone());
+    let id = cmd.get_id();
+    let (tx, rx) = oneshot::channel();
+    Store::instance().lock().unwrap().pending.insert(id, tx);
+    let _guard = PendingGuard(id);
+    rsm::propose(cmd)?;
+    match time::timeout(timeout, rx).await {
+        Ok(Ok(Ok(Commit{ result: Some(CommandResult::Duplicate), .. }))) => Err(Error::Duplicate),
+        Ok(Ok(Ok(Commit{ result: Some(CommandResult::Failed(err)), .. }))) => Err(err),
+        Ok(commit) => commit.unwrap_or(Err(Error::ProposalDropped)),
+        Err(_) => Err(Error::Timeout),
+    }
+}
 
-    // read entries that were decided in the meantime, to get latest previous value
-    prev_value = get_prev_value_after_decide(&kv.key, prev_value, prev_idx, idx);
-    Ok(prev_value.map(|value| KeyValue{key: kv.key, value}))
+/// Inserts into the replicated store, optionally attached to a lease
+/// returns the previous value of this key on success
+/// fails if the lease does not exist when the put is applied
+pub async fn put(kv: KeyValue, lease: Option<LeaseId>, opts: &ProposalOptions) -> Result<(PutResponse, u64)> {
+    let commit = propose(RSMCommand::new_put(kv, lease), opts).await?;
+    match commit.result {
+        Some(CommandResult::Put(resp)) => Ok((resp, commit.index)),
+        _ => unreachable!("puts always have a put result"),
+    }
 }
 
 /// Inserts into the replicated store
 /// returns the previous value of this key on success
-pub async fn delete(key: Key) -> Result<Option<KeyValue>,()> {
-    let mut prev_value = get(&key);
-    let prev_idx = RSM::instance().lock().unwrap().omnipaxos.get_decided_idx();
-    let idx = rsm::append(RSMCommand::new_delete(key.clone())).await?;
+pub async fn delete(key: Key, opts: &ProposalOptions) -> Result<(PutResponse, u64)> {
+    let commit = propose(RSMCommand::new_delete(key), opts).await?;
+    match commit.result {
+        Some(CommandResult::Put(resp)) => Ok((resp, commit.index)),
+        _ => unreachable!("deletes always have a put result"),
+    }
+}
 
-    // read entries that were decided in the meantime, to get latest previous value
-    prev_value = get_prev_value_after_decide(&key, prev_value, prev_idx, idx);
-    Ok(prev_value.map(|value| KeyValue{key, value}))
+/// Clears the replicated store, returns the log index it was applied at
+pub async fn clear(opts: &ProposalOptions) -> Result<u64> {
+    Ok(propose(RSMCommand::new_clear(), opts).await?.index)
 }
 
-/// Clears the replicated store
-pub async fn clear() -> Result<(), ()> {
-    rsm::append(RSMCommand::new_clear()).await?;
-    Ok(())
+/// Performs linearizable CAS operation, the condition is checked at the log position the CAS is decided at
+pub async fn cas(key: Key, new_value: Value, condition: CASCondition, opts: &ProposalOptions) -> Result<(CASResponse, u64)> {
+    let commit = propose(RSMCommand::new_cas(key, new_value, condition), opts).await?;
+    match commit.result {
+        Some(CommandResult::CAS(resp)) => Ok((resp, commit.index)),
+        _ => unreachable!("CAS always has a CAS result"),
+    }
 }
 
-/// Performs linearizable CAS operation
-/// returns the previous value of this key on success
-pub async fn cas(key: Key, new_value: Value, expected_value: Value) -> Result<Option<KeyValue>,()> {
-    let mut prev_value = get(&key);
-    let prev_idx = RSM::instance().lock().unwrap().omnipaxos.get_decided_idx();
-    let idx = rsm::append(RSMCommand::new_cas(key.clone(), new_value.clone(), expected_value.clone())).await?;
+/// Performs a transaction atomically at a single log position
+/// fails if the branch that runs puts a key onto a lease that does not exist
+pub async fn txn(txn: TxnRequest, opts: &ProposalOptions) -> Result<(TxnResponse, u64)> {
+    let commit = propose(RSMCommand::new_txn(txn), opts).await?;
+    match commit.result {
+        Some(CommandResult::Txn(resp)) => Ok((resp, commit.index)),
+        _ => unreachable!("transactions always have a transaction result"),
+    }
+}
+
+/// Grants a new lease with a TTL in seconds
+pub async fn lease_grant(ttl: u64, opts: &ProposalOptions) -> Result<(LeaseResponse, u64)> {
+    let commit = propose(RSMCommand::new_lease_grant(ttl), opts).await?;
+    match commit.result {
+        Some(CommandResult::LeaseGrant(id)) => Ok((get_lease(id)?, commit.index)),
+        _ => unreachable!("lease grants always have a lease result"),
+    }
+}
+
+/// Refreshes the TTL of a lease
+/// fails if the lease does not exist (anymore)
+pub async fn lease_keep_alive(id: LeaseId, opts: &ProposalOptions) -> Result<(LeaseResponse, u64)> {
+    let commit = propose(RSMCommand::new_lease_keep_alive(id), opts).await?;
+    Ok((get_lease(id)?, commit.index))
+}
+
+/// Revokes a lease and deletes all keys attached to it, returns the log index it was applied at
+pub async fn lease_revoke(id: LeaseId, opts: &ProposalOptions) -> Result<u64> {
+    Ok(propose(RSMCommand::new_lease_revoke(id), opts).await?.index)
+}
+
+/// Sequentially consistent read of a lease and its remaining TTL
+pub fn get_lease(id: LeaseId) -> Result<LeaseResponse> {
+    let unlocked = Store::instance();
+    let store = unlocked.lock().unwrap();
+    store.leases.get(&id).map(|lease| LeaseResponse{
+        id,
+        ttl: lease.ttl,
+        remaining_ttl: lease.deadline.saturating_duration_since(Instant::now()).as_secs(),
+        keys: lease.keys.iter().cloned().collect(),
+    }).ok_or(Error::LeaseNotFound(id))
+}
 
-    // read entries that were decided in the meantime, to get latest previous value
-    prev_value = get_prev_value_after_decide(&key, prev_value, prev_idx, idx);
-    Ok(prev_value.map(|value| KeyValue{key, value}))
+/// Compacts the decided log into a snapshot, either only on this node up to what it applied, or on the
+/// whole cluster up to what every replica applied, so no applier has to skip entries and drop its watches
+pub async fn snapshot(local_only: bool) -> Result<()> {
+    let applied = if local_only { applied_index() } else { cluster_applied_index().await? };
+    let unlocked = RSM::instance();
+    let mut rsm = unlocked.lock().unwrap();
+    if applied <= rsm.log_offset {
+        return Err(Error::Compaction("nothing of the current configuration has been applied everywhere yet".to_owned()))
+    }
+    let snapshot_idx = applied - rsm.log_offset;
+    rsm.omnipaxos.snapshot(Some(snapshot_idx), local_only)
+        .map_err(|err| Error::Compaction(format!("{:?}", err)))
+}
+
+/// The global index that every replica has applied
+async fn cluster_applied_index() -> Result<u64> {
+    let peers = rsm::query_peers::<u64>("/applied_index").await;
+    if peers.iter().any(Option::is_none) {
+        return Err(Error::Compaction("not every replica could be reached".to_owned()))
+    }
+    Ok(peers.into_iter().flatten().fold(applied_index(), u64::min))
 }
 
-pub async fn snapshot() -> Result<(), CompactionErr> {
-    RSM::instance().lock().unwrap().omnipaxos.snapshot(None, false)?;
-    Ok(())
+/// Trims the log of the whole cluster up to a global index that every replica has applied,
+/// by default as far as possible. Returns the index the log was trimmed up to.
+pub async fn trim(index: Option<u64>) -> Result<u64> {
+    let applied = cluster_applied_index().await?;
+    let index = index.unwrap_or(applied);
+    if index > applied {
+        return Err(Error::Compaction(format!("only index {} has been applied by every replica", applied)))
+    }
+    let unlocked = RSM::instance();
+    let mut rsm = unlocked.lock().unwrap();
+    if index < rsm.log_offset {
+        return Err(Error::InvalidRequest(format!("index {} is before the current configuration, which starts at {}", index, rsm.log_offset)))
+    }
+    let trim_idx = index - rsm.log_offset;
+    rsm.omnipaxos.trim(Some(trim_idx))
+        .map_err(|err| Error::Compaction(format!("{:?}", err)))?;
+    Ok(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use omnipaxos_core::storage::Snapshot;
+
+    /// Xorshift generator, so every failing case can be reproduced from its seed
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    /// A transaction that reads `key` and writes another key
+    fn random_txn(rng: &mut Rng, key: Key, value: Value) -> TxnRequest {
+        let other = format!("k{}", rng.below(4));
+        let target = match rng.below(4) {
+            0 => CompareTarget::Value(value.clone()),
+            1 => CompareTarget::Version(rng.below(3)),
+            2 => CompareTarget::ModRevision(0),
+            _ => CompareTarget::Exists(true),
+        };
+        TxnRequest{
+            compare: vec![Compare{ key, op: CompareOp::Equal, target }],
+            success: vec![TxnOp::Put(PutRequest{ key: other.clone(), value, lease: None })],
+            failure: vec![TxnOp::Delete{ key: other }],
+        }
+    }
+
+    /// Random commands on a few keys, leases and sessions, as decided at the log positions after `offset`
+    fn random_log(rng: &mut Rng, len: u64, offset: Revision) -> Vec<RSMCommand> {
+        let mut leases = vec![0];
+        let mut log = vec![];
+        for i in 0..len {
+            let id = (0, offset + i);
+            let revision = offset + i + 1;
+            let key = format!("k{}", rng.below(4));
+            let value = format!("v{}", rng.below(3));
+            let lease = leases[rng.below(leases.len() as u64) as usize];
+            let cmd = match rng.below(16) {
+                0..=3 => RSMCommand::Put((id, KeyValue{ key, value }, None)),
+                4 => RSMCommand::Put((id, KeyValue{ key, value }, Some(lease))),
+                5 | 6 => RSMCommand::Delete((id, key)),
+                7 | 8 => {
+                    let condition = match rng.below(4) {
+                        0 => CASCondition::Value(value.clone()),
+                        1 => CASCondition::Absent,
+                        2 => CASCondition::Version(rng.below(3)),
+                        _ => CASCondition::ModRevision(revision.saturating_sub(rng.below(8))),
+                    };
+                    RSMCommand::CAS((id, KeyValue{ key, value }, condition))
+                },
+                9 | 10 => RSMCommand::Txn((id, random_txn(rng, key, value))),
+                11 => {
+                    leases.push(revision);
+                    RSMCommand::LeaseGrant((id, 60))
+                },
+                12 => RSMCommand::LeaseRevoke((id, lease)),
+                13 => if rng.below(3) == 0 { RSMCommand::Clear(id) } else { RSMCommand::LinearizableRead(id) },
+                14 => RSMCommand::LeaseKeepAlive((id, lease)),
+                _ => RSMCommand::ExpireSessions((id, vec![format!("s{}", rng.below(2))])),
+            };
+            // sequence numbers come from a small range, so there are plenty of retries
+            let cmd = if !matches!(cmd, RSMCommand::ExpireSessions(_)) && rng.below(3) == 0 {
+                cmd.with_session(Some(Session{ id: format!("s{}", rng.below(2)), seq: rng.below(6) }))
+            } else {
+                cmd
+            };
+            log.push(cmd);
+        }
+        log
+    }
+
+    /// Applies commands like the applier task does, and returns the index and result of every command,
+    /// and whether it retried a session command
+    fn apply(store: &mut Store, cmds: &[RSMCommand]) -> Vec<(u64, String, bool)> {
+        let mut results = vec![];
+        for cmd in cmds {
+            store.applied_log_index += 1;
+            let revision = store.revision();
+            let retry = match cmd {
+                RSMCommand::Session((session, _)) => store.sessions.get(&session.id).is_some_and(|known| known.seq >= session.seq),
+                _ => false,
+            };
+            let (_, result) = store.apply_command(cmd.clone(), revision);
+            results.push((revision, format!("{:?}", result), retry));
+        }
+        results
+    }
+
+    /// Snapshots the first `split` commands of a log in random chunks
+    fn random_snapshot(rng: &mut Rng, log: &[RSMCommand], split: usize) -> OPSnapshot {
+        let mut to = (1 + rng.below(10) as usize).min(split);
+        let mut snapshot = OPSnapshot::create(&log[..to]);
+        while to < split {
+            let from = to;
+            to = (from + 1 + rng.below(10) as usize).min(split);
+            snapshot.merge(OPSnapshot::create(&log[from..to]));
+        }
+        snapshot
+    }
+
+    /// Results have to be the same, except that a retry may have lost the result of its first attempt
+    fn assert_results_agree(actual: &[(u64, String, bool)], expected: &[(u64, String, bool)], seed: u64) {
+        assert_eq!(actual.len(), expected.len(), "seed {}", seed);
+        let duplicate = format!("{:?}", Some(CommandResult::Duplicate));
+        for (actual, expected) in actual.iter().zip(expected) {
+            if !(expected.2 && actual.1 == duplicate) {
+                assert_eq!(actual.0, expected.0, "seed {}", seed);
+                assert_eq!(actual.1, expected.1, "seed {}", seed);
+            }
+        }
+    }
+
+    /// Current key versions, keys of every lease and last sequence number of every session
+    type Observed = (Vec<(Key, KeyVersion)>, BTreeMap<LeaseId, BTreeSet<Key>>, BTreeMap<String, u64>);
+
+    /// Everything about a store that later commands and reads depend on
+    fn observe(store: &Store) -> Observed {
+        let keys = store.map.keys().filter_map(|key| store.current(key).map(|v| (key.clone(), v.clone()))).collect();
+        let leases = store.leases.iter().map(|(id, lease)| (*id, lease.keys.clone())).collect();
+        let sessions = store.sessions.iter().map(|(id, session)| (id.clone(), session.seq)).collect();
+        (keys, leases, sessions)
+    }
+
+    /// A log of the first configuration, or of a later one that starts with the state of a random previous one
+    fn random_config(rng: &mut Rng) -> (Revision, Vec<RSMCommand>) {
+        if rng.below(2) == 0 {
+            return (0, vec![])
+        }
+        let mut previous = Store::new();
+        let len = rng.below(20);
+        apply(&mut previous, &random_log(rng, len, 0));
+        let state = previous.state();
+        (state.revision, vec![RSMCommand::Migrate(((1, 0), state))])
+    }
+
+    /// Runs random logs through every way a replica can get to a state: applying the log, restoring a snapshot
+    /// of a prefix, built in random chunks, and installing the state of a peer that applied a prefix. Applying the
+    /// rest of the log on top has to give the same store and the same command results as applying the whole log.
+    #[test]
+    fn restored_snapshots_match_the_applied_log() {
+        for seed in 1..=2000 {
+            let mut rng = Rng(seed);
+            let (offset, mut log) = random_config(&mut rng);
+            let len = rng.below(60);
+            log.extend(random_log(&mut rng, len, offset));
+
+            let mut expected = Store::new();
+            expected.log_offset = offset;
+            let expected_results = apply(&mut expected, &log);
+
+            // restoring a snapshot resolves the commands it still replays
+            let split = rng.below(log.len() as u64 + 1) as usize;
+            let snapshot = random_snapshot(&mut rng, &log, split);
+            let mut restored = Store::new();
+            restored.log_offset = offset;
+            let mut receivers = vec![];
+            for cmd in &log[..split] {
+                let (tx, rx) = oneshot::channel();
+                restored.pending.insert(cmd.get_id(), tx);
+                receivers.push(rx);
+            }
+            restored.restore_snapshot(snapshot, split as u64);
+            let mut results = vec![];
+            let mut replayed = vec![];
+            for (i, mut rx) in receivers.into_iter().enumerate() {
+                if let Ok(Ok(commit)) = rx.try_recv() {
+                    results.push((commit.index, format!("{:?}", commit.result), false));
+                    replayed.push(expected_results[i].clone());
+                }
+            }
+            assert_results_agree(&results, &replayed, seed);
+            let results = apply(&mut restored, &log[split..]);
+            assert_results_agree(&results, &expected_results[split..], seed);
+            assert_eq!(observe(&restored), observe(&expected), "seed {}", seed);
+
+            // a replica that fell behind a trim installs the state of a peer
+            let split = rng.below(log.len() as u64 + 1) as usize;
+            let mut peer = Store::new();
+            peer.log_offset = offset;
+            apply(&mut peer, &log[..split]);
+            let mut installed = Store::new();
+            installed.log_offset = offset;
+            installed.install(peer.state());
+            let results = apply(&mut installed, &log[split..]);
+            assert_results_agree(&results, &expected_results[split..], seed);
+            assert_eq!(observe(&installed), observe(&expected), "seed {}", seed);
+        }
+    }
+
+    /// A store recovered from a persisted checkpoint of a random prefix continues like the original
+    #[test]
+    fn checkpoints_resume_the_applied_log() {
+        for seed in 1..=500 {
+            let mut rng = Rng(seed);
+            let (offset, mut log) = random_config(&mut rng);
+            let len = rng.below(60);
+            log.extend(random_log(&mut rng, len, offset));
+
+            let mut expected = Store::new();
+            expected.log_offset = offset;
+            let split = rng.below(log.len() as u64 + 1) as usize;
+            apply(&mut expected, &log[..split]);
+            let bytes = serde_json::to_vec(&expected.checkpoint(1)).unwrap();
+            let expected_results = apply(&mut expected, &log[split..]);
+
+            let mut recovered = Store::new();
+            recovered.recover(serde_json::from_slice(&bytes).unwrap());
+            let results = apply(&mut recovered, &log[split..]);
+            assert_results_agree(&results, &expected_results, seed);
+            assert_eq!(observe(&recovered), observe(&expected), "seed {}", seed);
+            assert_eq!(recovered.compact_revision, offset + split as u64, "seed {}", seed);
+        }
+    }
+
+    /// Applies a single command like the applier task does
+    fn run(store: &mut Store, cmd: RSMCommand) -> (Vec<WatchEvent>, Option<CommandResult>) {
+        store.applied_log_index += 1;
+        let revision = store.revision();
+        store.apply_command(cmd, revision)
+    }
+
+    fn kv(key: &str, value: &str) -> KeyValue {
+        KeyValue{ key: key.to_owned(), value: value.to_owned() }
+    }
+
+    fn txn_put(key: &str, value: &str, lease: Option<LeaseId>) -> TxnOp {
+        TxnOp::Put(PutRequest{ key: key.to_owned(), value: value.to_owned(), lease })
+    }
+
+    fn range_request(key: &str) -> RangeRequest {
+        RangeRequest{ key: key.to_owned(), range_end: None, prefix: false, limit: 0, keys_only: false, count_only: false, revision: None }
+    }
+
+    fn txn_range(key: &str) -> TxnOp {
+        TxnOp::Range(range_request(key))
+    }
+
+    fn value(store: &Store, key: &str) -> Option<Value> {
+        store.current(&key.to_owned()).and_then(|v| v.value.clone())
+    }
+
+    /// A transaction runs its success branch if all compares hold and its failure branch otherwise,
+    /// ranges see the writes before them, and a put onto a missing lease fails the whole transaction
+    #[test]
+    fn transactions_run_the_branch_their_compares_choose() {
+        let mut store = Store::new();
+        run(&mut store, RSMCommand::Put(((0, 0), kv("a", "1"), None)));
+        let txn = |i, target, lease| RSMCommand::Txn(((0, i), TxnRequest{
+            compare: vec![Compare{ key: "a".to_owned(), op: CompareOp::Equal, target }],
+            success: vec![txn_put("b", "yes", lease), txn_range("b")],
+            failure: vec![TxnOp::Delete{ key: "b".to_owned() }, txn_range("b")],
+        }));
+
+        match run(&mut store, txn(1, CompareTarget::Value("1".to_owned()), None)) {
+            (events, Some(CommandResult::Txn(resp))) => {
+                assert!(resp.succeeded);
+                assert_eq!(resp.revision, 2);
+                assert_eq!(events.len(), 1);
+                match &resp.responses[..] {
+                    [TxnOpResponse::Put(put), TxnOpResponse::Range(range)] => {
+                        assert!(put.prev_kv.is_none());
+                        assert_eq!(range.kvs.iter().map(|kv| kv.value.as_str()).collect::<Vec<_>>(), vec!["yes"]);
+                    },
+                    responses => panic!("unexpected {:?}", responses),
+                }
+            },
+            result => panic!("unexpected {:?}", result),
+        }
+
+        match run(&mut store, txn(2, CompareTarget::Version(2), None)) {
+            (_, Some(CommandResult::Txn(resp))) => {
+                assert!(!resp.succeeded);
+                match &resp.responses[..] {
+                    [TxnOpResponse::Delete(delete), TxnOpResponse::Range(range)] => {
+                        assert_eq!(delete.prev_kv.as_ref().map(|kv| kv.value.as_str()), Some("yes"));
+                        assert!(range.kvs.is_empty());
+                    },
+                    responses => panic!("unexpected {:?}", responses),
+                }
+            },
+            result => panic!("unexpected {:?}", result),
+        }
+        assert_eq!(value(&store, "b"), None);
+
+        match run(&mut store, txn(3, CompareTarget::Exists(true), Some(7))) {
+            (events, Some(CommandResult::Failed(Error::LeaseNotFound(7)))) => assert!(events.is_empty()),
+            result => panic!("unexpected {:?}", result),
+        }
+        assert_eq!(value(&store, "b"), None);
+        assert_eq!(value(&store, "a").as_deref(), Some("1"));
+    }
+
+    /// Past revisions can be read until they are compacted, the compact revision itself stays readable
+    #[test]
+    fn historical_reads_fail_past_the_compaction() {
+        let mut store = Store::new();
+        run(&mut store, RSMCommand::Put(((0, 0), kv("k", "v1"), None)));
+        run(&mut store, RSMCommand::Put(((0, 1), kv("k", "v2"), None)));
+        run(&mut store, RSMCommand::Delete(((0, 2), "k".to_owned())));
+        run(&mut store, RSMCommand::Put(((0, 3), kv("k", "v4"), None)));
+        let read = |store: &Store, revision| store.read(&"k".to_owned(), revision).map(|resp| resp.value);
+
+        assert_eq!(read(&store, 1).unwrap().as_deref(), Some("v1"));
+        assert_eq!(read(&store, 2).unwrap().as_deref(), Some("v2"));
+        assert_eq!(read(&store, 3).unwrap(), None);
+        let resp = store.read(&"k".to_owned(), 4).unwrap();
+        assert_eq!((resp.value.as_deref(), resp.create_revision, resp.version), (Some("v4"), 4, 1));
+
+        store.compact(2);
+        assert!(matches!(read(&store, 1), Err(Error::Compacted(2))));
+        assert_eq!(read(&store, 2).unwrap().as_deref(), Some("v2"));
+        assert_eq!(read(&store, 3).unwrap(), None);
+        assert_eq!(store.range(&range_request("k"), 2).unwrap().count, 1);
+        assert!(matches!(store.range(&range_request("k"), 1), Err(Error::Compacted(2))));
+        assert!(matches!(read(&store, 5), Err(Error::InvalidRequest(_))));
+    }
+
+    /// A lease is due once its TTL ran out without a keep alive, and revoking it deletes exactly its keys
+    #[test]
+    fn expired_leases_delete_their_keys() {
+        let mut store = Store::new();
+        let id = match run(&mut store, RSMCommand::LeaseGrant(((0, 0), 1))) {
+            (_, Some(CommandResult::LeaseGrant(id))) => id,
+            result => panic!("unexpected {:?}", result),
+        };
+        run(&mut store, RSMCommand::Put(((0, 1), kv("leased", "v"), Some(id))));
+        run(&mut store, RSMCommand::Put(((0, 2), kv("kept", "v"), None)));
+
+        let now = Instant::now();
+        assert!(store.due_leases(now).is_empty());
+        run(&mut store, RSMCommand::LeaseKeepAlive(((0, 3), id)));
+        assert!(store.due_leases(now + Duration::from_millis(500)).is_empty());
+        let later = Instant::now() + Duration::from_secs(2);
+        assert_eq!(store.due_leases(later), vec![id]);
+        // the deadline moved on, so a lost revocation is not proposed again right away
+        assert!(store.due_leases(later).is_empty());
+
+        let (events, _) = run(&mut store, RSMCommand::LeaseRevoke(((0, 4), id)));
+        assert_eq!(events.iter().map(|e| e.key.clone()).collect::<Vec<_>>(), vec![Some("leased".to_owned())]);
+        assert_eq!(value(&store, "leased"), None);
+        assert_eq!(value(&store, "kept").as_deref(), Some("v"));
+        assert!(store.leases.is_empty());
+        assert!(matches!(run(&mut store, RSMCommand::Put(((0, 5), kv("leased", "v"), Some(id)))), (_, Some(CommandResult::Failed(Error::LeaseNotFound(_))))));
+    }
 }
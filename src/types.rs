@@ -1,7 +1,20 @@
 use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
 
 pub type Key = String;
 pub type Value = String; // TODO: different type?, maybe json?
+/// Cluster-wide revision, every applied log entry bumps it by one
+pub type Revision = u64;
+/// Leases are identified by the revision at which they were granted
+pub type LeaseId = u64;
+
+/// Identifies a request of a client session. Commands are applied at most once per session and sequence number,
+/// so clients can safely retry them with the same sequence number, also against another node.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Session {
+    pub id: String,
+    pub seq: u64,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct KeyValue {
@@ -9,26 +22,369 @@ pub struct KeyValue {
     pub value: Value,
 }
 
+/// A key-value pair together with its MVCC metadata
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VersionedKeyValue {
+    pub key: Key,
+    pub value: Value,
+    pub create_revision: Revision,
+    pub mod_revision: Revision,
+    pub version: u64,
+    pub lease: Option<LeaseId>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GetParams {
+    pub revision: Option<Revision>,
+}
+
+/// How a linearizable read makes sure it sees every write that finished before it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadMode {
+    /// the leader confirms with a quorum that it still leads
+    ReadIndex,
+    /// the leader serves the read locally while its lease holds, otherwise it goes through the log
+    Lease,
+    /// the read is decided as a log entry
+    Log,
+}
+
+/// How up to date a read has to be
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Consistency {
+    /// whatever this replica has applied so far
+    Local,
+    /// everything this replica knows to be decided is applied first
+    Sequential,
+    /// like sequential, but fails if the replica lags the leader by more than `max_lag` entries or `max_lag_ms`
+    BoundedStaleness,
+    Linearizable,
+}
+
+/// Query parameters that choose the consistency of a read, sequential by default
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ConsistencyParams {
+    pub consistency: Option<Consistency>,
+    /// how linearizable reads are served
+    pub mode: Option<ReadMode>,
+    pub max_lag: Option<u64>,
+    pub max_lag_ms: Option<u64>,
+    /// the read waits until the replica has applied this global log index, e.g. one returned by a write
+    pub min_index: Option<u64>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct GetResponse {
     pub key: Key,
-    pub value: Option<Value>
+    pub value: Option<Value>,
+    pub create_revision: Revision,
+    pub mod_revision: Revision,
+    pub version: u64,
+    pub lease: Option<LeaseId>,
+    /// the revision of the store this read was served at
+    pub revision: Revision,
+    /// the global log index the replica had applied when serving this read
+    pub applied_index: u64,
+}
+
+impl GetResponse {
+    pub fn empty(key: Key) -> Self {
+        Self { key, value: None, create_revision: 0, mod_revision: 0, version: 0, lease: None, revision: 0, applied_index: 0 }
+    }
+}
+
+/// Reads every key in `[key, range_end)`, or every key starting with `key` if `prefix` is set.
+/// Without a `range_end` only `key` itself is read, and a `range_end` of `"\0"` reads all keys from `key` on.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RangeRequest {
+    pub key: Key,
+    pub range_end: Option<Key>,
+    #[serde(default)]
+    pub prefix: bool,
+    /// maximum number of keys to return, 0 means no limit
+    #[serde(default)]
+    pub limit: u64,
+    #[serde(default)]
+    pub keys_only: bool,
+    #[serde(default)]
+    pub count_only: bool,
+    pub revision: Option<Revision>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RangeResponse {
+    /// matching keys in ascending key order
+    pub kvs: Vec<VersionedKeyValue>,
+    /// number of keys in the range, regardless of `limit`
+    pub count: u64,
+    /// whether `limit` cut off more keys
+    pub more: bool,
+    pub revision: Revision,
+    /// the global log index the replica had applied when serving this read
+    pub applied_index: u64,
+}
+
+/// Watches a key, or all keys starting with `key` if `prefix` is set.
+/// With a `start_revision`, past events from that revision on are streamed first.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WatchRequest {
+    pub key: Key,
+    #[serde(default)]
+    pub prefix: bool,
+    pub start_revision: Option<Revision>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum EventKind {
+    Put,
+    CAS,
+    Delete,
+    Clear,
+}
+
+/// A change to the store, as streamed to watchers
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WatchEvent {
+    pub kind: EventKind,
+    /// the changed key, `None` for clear
+    pub key: Option<Key>,
+    /// the new value, `None` for delete and clear
+    pub value: Option<Value>,
+    pub revision: Revision,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PutRequest {
     pub key: Key,
     pub value: Value,
+    /// attaches the key to a lease, so it is deleted when the lease expires
+    pub lease: Option<LeaseId>,
 }
 
+/// Swaps in `new_value` if exactly one of the conditions holds when the CAS is decided
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CASRequest {
     pub key: Key,
     pub new_value: Value,
-    pub expected_value: Value,
+    pub expected_value: Option<Value>,
+    pub expected_version: Option<u64>,
+    pub expected_mod_revision: Option<Revision>,
+    /// only create the key if it does not exist yet
+    #[serde(default)]
+    pub create_only: bool,
+}
+
+impl CASRequest {
+    /// The condition of this CAS, `None` unless exactly one is given
+    pub fn condition(&self) -> Option<CASCondition> {
+        let mut conditions = vec![];
+        if let Some(ref value) = self.expected_value {
+            conditions.push(CASCondition::Value(value.clone()));
+        }
+        if let Some(version) = self.expected_version {
+            conditions.push(CASCondition::Version(version));
+        }
+        if let Some(mod_revision) = self.expected_mod_revision {
+            conditions.push(CASCondition::ModRevision(mod_revision));
+        }
+        if self.create_only {
+            conditions.push(CASCondition::Absent);
+        }
+        if conditions.len() == 1 { conditions.pop() } else { None }
+    }
+}
+
+/// What a CAS checks the current state of its key against
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum CASCondition {
+    Value(Value),
+    /// the key does not exist
+    Absent,
+    Version(u64),
+    ModRevision(Revision),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CASResponse {
+    pub succeeded: bool,
+    /// the key right before the CAS was applied, so the current key if the CAS did not succeed
+    pub prev_kv: Option<VersionedKeyValue>,
+    pub revision: Revision,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PutResponse {
-    pub prev_kv: Option<KeyValue>
+    pub prev_kv: Option<VersionedKeyValue>,
+    /// the revision at which this operation was applied
+    pub revision: Revision,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LeaseGrantRequest {
+    /// time to live in seconds
+    pub ttl: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LeaseResponse {
+    pub id: LeaseId,
+    /// granted time to live in seconds
+    pub ttl: u64,
+    /// remaining time to live in seconds, as seen by the serving node
+    pub remaining_ttl: u64,
+    pub keys: Vec<Key>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum CompareOp {
+    Equal,
+    NotEqual,
+    Greater,
+    Less,
+}
+
+/// What a compare checks. Missing keys have version and mod_revision 0,
+/// and only compare not equal to any value.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum CompareTarget {
+    Value(Value),
+    Version(u64),
+    ModRevision(Revision),
+    Exists(bool),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Compare {
+    pub key: Key,
+    pub op: CompareOp,
+    pub target: CompareTarget,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum TxnOp {
+    Put(PutRequest),
+    Delete { key: Key },
+    /// ranges always read at the revision of the transaction, their `revision` is ignored
+    Range(RangeRequest),
+}
+
+/// Runs `success` if all compares hold, `failure` otherwise, atomically at a single revision
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TxnRequest {
+    #[serde(default)]
+    pub compare: Vec<Compare>,
+    #[serde(default)]
+    pub success: Vec<TxnOp>,
+    #[serde(default)]
+    pub failure: Vec<TxnOp>,
+}
+
+impl TxnRequest {
+    /// All keys whose state this transaction depends on or may change
+    pub fn keys(&self) -> Vec<Key> {
+        let mut keys: Vec<Key> = self.compare.iter().map(|cmp| cmp.key.clone())
+            .chain(self.success.iter().chain(self.failure.iter()).filter_map(|op| match op {
+                TxnOp::Put(req) => Some(req.key.clone()),
+                TxnOp::Delete { key } => Some(key.clone()),
+                TxnOp::Range(_) => None,
+            }))
+            .collect();
+        keys.sort();
+        keys.dedup();
+        keys
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum TxnOpResponse {
+    Put(PutResponse),
+    Delete(PutResponse),
+    Range(RangeResponse),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TxnResponse {
+    pub succeeded: bool,
+    /// one response per operation of the branch that ran
+    pub responses: Vec<TxnOpResponse>,
+    pub revision: Revision,
+}
+
+/// Replaces the cluster membership, `nodes` maps every node of the new configuration to its address
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReconfigureRequest {
+    pub nodes: HashMap<u64, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AddNodeRequest {
+    pub id: u64,
+    pub addr: String,
+}
+
+/// Swaps node `old` for node `new`, which is reachable at `addr`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReplaceNodeRequest {
+    pub old: u64,
+    pub new: u64,
+    pub addr: String,
+}
+
+/// Trims the log up to a global log index, by default up to the index every replica has applied
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TrimRequest {
+    pub index: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TrimResponse {
+    /// the global log index the log was trimmed up to
+    pub index: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum SnapshotOutcome {
+    Compacted,
+    Skipped(String),
+    Failed(String),
+}
+
+/// A run of the snapshot policy, or a manual snapshot
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnapshotRun {
+    /// unix time in millis
+    pub at: u64,
+    /// why the snapshot was taken
+    pub reason: String,
+    /// the global log index decided at the time
+    pub decided_index: u64,
+    pub local_only: bool,
+    pub outcome: SnapshotOutcome,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnapshotStatus {
+    /// decided entries that are not compacted yet
+    pub entries: u64,
+    /// their size as json, only counted if SNAPSHOT_BYTES is set
+    pub bytes: u64,
+    pub last_run: Option<SnapshotRun>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LeaderResponse {
+    /// the current ballot leader, if there is one
+    pub leader: Option<u64>,
+    /// address of the leader, if it is known and not the responding node
+    pub addr: Option<String>,
+    /// the responding node
+    pub node: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConfigResponse {
+    pub config_id: u32,
+    pub nodes: Vec<u64>,
 }
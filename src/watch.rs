@@ -0,0 +1,179 @@
+use crate::types::*;
+use crate::error::{Error, Result};
+use tokio::sync::mpsc;
+use std::{env, sync::{Arc, Mutex}, collections::VecDeque};
+
+lazy_static! {
+    static ref WATCH_BUFFER: usize = if let Ok(var) = env::var("WATCH_BUFFER") {
+        var.parse().expect("WATCH_BUFFER must be usize")
+    } else {
+        1024
+    };
+}
+
+static mut INSTANCE: Option<Arc<Mutex<Watchers>>> = None;
+
+struct Watcher {
+    key: Key,
+    prefix: bool,
+    /// events before this revision are not delivered
+    start_revision: Revision,
+    tx: mpsc::Sender<WatchEvent>,
+}
+
+impl Watcher {
+    fn matches(&self, event: &WatchEvent) -> bool {
+        if event.revision < self.start_revision {
+            return false
+        }
+        match event.key {
+            None => true, // clear affects every key
+            Some(ref key) => if self.prefix { key.starts_with(&self.key) } else { *key == self.key },
+        }
+    }
+}
+
+/// All active watchers, plus the events that are still available for resuming a watch
+struct Watchers {
+    watchers: Vec<Watcher>,
+    history: VecDeque<WatchEvent>,
+    /// events up to this revision are no longer in the history
+    compact_revision: Revision,
+}
+
+impl Watchers {
+    fn new() -> Self {
+        Watchers{
+            watchers: vec![],
+            history: VecDeque::new(),
+            compact_revision: 0,
+        }
+    }
+
+    /// Get the singleton Watchers instance
+    fn instance() -> Arc<Mutex<Self>> {
+        unsafe {
+            if let Some(ref watchers) = INSTANCE {
+                watchers.clone()
+            } else {
+                let watchers = Arc::new(Mutex::new(Watchers::new()));
+                INSTANCE = Some(watchers.clone());
+                watchers
+            }
+        }
+    }
+
+    fn notify(&mut self, events: Vec<WatchEvent>) {
+        self.watchers.retain(|w| {
+            let matching: Vec<&WatchEvent> = events.iter().filter(|e| w.matches(e)).collect();
+            // a revision is delivered as a whole or not at all, so resuming after it is safe
+            if matching.len() > w.tx.capacity() {
+                return false
+            }
+            matching.into_iter().all(|e| w.tx.try_send(e.clone()).is_ok())
+        });
+        self.history.extend(events);
+    }
+
+    fn compact(&mut self, revision: Revision) {
+        while let Some(event) = self.history.front() {
+            if event.revision > revision {
+                break
+            }
+            self.history.pop_front();
+        }
+        self.compact_revision = revision;
+    }
+
+    fn reset(&mut self, revision: Revision) {
+        self.watchers.clear();
+        self.history.clear();
+        self.compact_revision = revision;
+    }
+
+    fn subscribe(&mut self, key: Key, prefix: bool, start_revision: Option<Revision>) -> Result<(Vec<WatchEvent>, mpsc::Receiver<WatchEvent>)> {
+        let (tx, rx) = mpsc::channel(*WATCH_BUFFER);
+        let mut watcher = Watcher{ key, prefix, start_revision: 0, tx };
+
+        let mut backlog = vec![];
+        if let Some(start_revision) = start_revision {
+            if start_revision <= self.compact_revision && self.compact_revision > 0 {
+                return Err(Error::Compacted(self.compact_revision + 1))
+            }
+            watcher.start_revision = start_revision;
+            backlog = self.history.iter().filter(|e| watcher.matches(e)).cloned().collect();
+        }
+        self.watchers.push(watcher);
+        Ok((backlog, rx))
+    }
+}
+
+/// Delivers the events of one applied revision to all matching watchers and keeps them for resuming watches.
+/// Watchers that fall behind by more than WATCH_BUFFER events are dropped, which ends their
+/// stream, so clients have to resume from the last revision they have seen.
+pub fn notify(events: Vec<WatchEvent>) {
+    Watchers::instance().lock().unwrap().notify(events)
+}
+
+/// Forgets all events up to the given revision
+pub fn compact(revision: Revision) {
+    Watchers::instance().lock().unwrap().compact(revision)
+}
+
+/// Drops all watchers and history, used when the store skips ahead to a snapshot
+pub fn reset(revision: Revision) {
+    Watchers::instance().lock().unwrap().reset(revision)
+}
+
+/// Registers a new watcher on a key or prefix. With a `start_revision`, all retained events from that
+/// revision on are returned first, and only later events are sent through the channel.
+/// fails if events from the start revision have already been compacted
+pub fn subscribe(key: Key, prefix: bool, start_revision: Option<Revision>) -> Result<(Vec<WatchEvent>, mpsc::Receiver<WatchEvent>)> {
+    Watchers::instance().lock().unwrap().subscribe(key, prefix, start_revision)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(key: &str, revision: Revision) -> WatchEvent {
+        WatchEvent{ kind: EventKind::Put, key: Some(key.to_owned()), value: Some(revision.to_string()), revision }
+    }
+
+    fn revisions(events: &[WatchEvent]) -> Vec<Revision> {
+        events.iter().map(|e| e.revision).collect()
+    }
+
+    /// A resumed watch first gets the retained events of its key from the start revision on, then the live ones
+    #[test]
+    fn watches_resume_from_their_start_revision() {
+        let mut watchers = Watchers::new();
+        for revision in 1..=5 {
+            watchers.notify(vec![put("a", revision), put("b", revision)]);
+        }
+        let (backlog, mut rx) = watchers.subscribe("a".to_owned(), false, Some(3)).unwrap();
+        assert_eq!(revisions(&backlog), vec![3, 4, 5]);
+        assert!(backlog.iter().all(|e| e.key.as_deref() == Some("a")));
+
+        watchers.notify(vec![put("b", 6)]);
+        watchers.notify(vec![put("a", 7), WatchEvent{ kind: EventKind::Clear, key: None, value: None, revision: 7 }]);
+        let mut live = vec![];
+        while let Ok(event) = rx.try_recv() {
+            live.push(event);
+        }
+        assert_eq!(revisions(&live), vec![7, 7]);
+    }
+
+    /// Events that were compacted cannot be resumed from, later ones still can
+    #[test]
+    fn watches_cannot_resume_from_compacted_revisions() {
+        let mut watchers = Watchers::new();
+        for revision in 1..=5 {
+            watchers.notify(vec![put("a", revision)]);
+        }
+        watchers.compact(3);
+        assert!(matches!(watchers.subscribe("a".to_owned(), false, Some(3)), Err(Error::Compacted(4))));
+        let (backlog, _rx) = watchers.subscribe("a".to_owned(), true, Some(4)).unwrap();
+        assert_eq!(revisions(&backlog), vec![4, 5]);
+    }
+}
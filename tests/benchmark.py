@@ -0,0 +1,48 @@
+from time import time
+from statistics import mean, quantiles
+from util import (
+    put,
+    read,
+    new_session,
+    collect_results,
+)
+
+ROUNDS = 20
+CONCURRENCY = 50
+NODES = [1, 2, 3]
+
+### Measures request latency and throughput of a healthy cluster
+
+def run_round(session, round):
+    futures_list = []
+    for i in range(CONCURRENCY):
+        node = NODES[i % len(NODES)]
+        if i % 2 == 0:
+            put(session, futures_list, node, f"bench{i}", f"{round}")
+        else:
+            read(session, futures_list, node, f"bench{i-1}")
+    return collect_results(futures_list)
+
+def print_stats(op, latencies):
+    if len(latencies) < 2:
+        print(f"{op}: not enough completed requests")
+        return
+    p = quantiles(latencies, n=100)
+    print(f"{op}: {len(latencies)} requests, mean {mean(latencies)*1000:.1f}ms, p50 {p[49]*1000:.1f}ms, p99 {p[98]*1000:.1f}ms")
+
+session = new_session()
+latencies = {"put": [], "read": []}
+failed = 0
+start = time()
+for round in range(ROUNDS):
+    for r in run_round(session, round):
+        if r["result"] is None:
+            failed += 1
+        else:
+            latencies[r["op"]].append(r["end"] - r["start"])
+duration = time() - start
+
+for op in latencies:
+    print_stats(op, latencies[op])
+completed = sum(len(l) for l in latencies.values())
+print(f"throughput: {completed / duration:.1f} requests/s, {failed} requests failed")
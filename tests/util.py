@@ -93,6 +93,20 @@ def delete(session, futures_list, node, key):
     futures_list.append(future)
     return future
 
+def normalize(op, result):
+    """
+    Strips a response down to the fields that the linearizability checker models,
+    the MVCC metadata (revisions, versions) is not part of the checked state.
+    """
+    if op in ["put", "cas", "delete"]:
+        prev_kv = result["prev_kv"]
+        if prev_kv is not None:
+            prev_kv = {"key": prev_kv["key"], "value": prev_kv["value"]}
+        return {"prev_kv": prev_kv}
+    if op == "read":
+        return {"key": result["key"], "value": result["value"]}
+    return result
+
 def collect_results(futures_list):
     results_list = []
     (done, not_done) = wait(futures_list, timeout=TIMEOUT, return_when=FIRST_EXCEPTION)
@@ -103,6 +117,10 @@ def collect_results(futures_list):
         except:
             not_done.add(f)
             continue
+        # a request that timed out on the server may still take effect later
+        if r.status_code == 504:
+            not_done.add(f)
+            continue
         if r.ok:
             results_list.append({
                 "start": f.start,
@@ -110,7 +128,7 @@ def collect_results(futures_list):
                 "node": f.node,
                 "op": f.op,
                 "input": f.input,
-                "result": r.json(),
+                "result": normalize(f.op, r.json()),
             })
     for f in not_done:
         results_list.append({